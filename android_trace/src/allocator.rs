@@ -0,0 +1,272 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A global allocator which records heap usage using Android Trace.
+//!
+//! See [`TracingAllocator`] for details.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    cell::Cell,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::AndroidTrace;
+
+/// A [`GlobalAlloc`] which wraps another allocator, and records the heap usage as counters.
+///
+/// The following counters are recorded, at most once per [update interval](Self::with_update_interval)
+/// (10ms by default):
+/// - `heap.live_bytes`: The number of bytes currently allocated.
+/// - `heap.allocs`: The number of allocations which have not yet been freed.
+///
+/// The counters are updated from inside the allocator, when an allocation is made or freed after the
+/// update interval has elapsed, so they are not updated whilst the process isn't allocating.
+///
+/// This also tracks the number of bytes allocated by each thread, which is available from
+/// [`thread_allocated_bytes`].
+/// This can be used to find the bytes allocated within a section, such as by the
+/// `with_allocation_counters` option of `tracing_android_trace`'s `AndroidTraceLayer`.
+///
+/// ## Usage
+///
+/// ```no_run
+/// use android_trace::allocator::TracingAllocator;
+/// use std::{alloc::System, time::Duration};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TracingAllocator<System> =
+///     TracingAllocator::new(System).with_update_interval(Duration::from_millis(1));
+/// ```
+#[derive(Debug)]
+pub struct TracingAllocator<A> {
+    inner: A,
+    update_interval: Duration,
+    live_bytes: AtomicUsize,
+    live_allocations: AtomicUsize,
+    /// The time of the last counter update, in nanoseconds since `epoch`.
+    last_update: AtomicU64,
+    epoch: OnceLock<Instant>,
+    trace: OnceLock<AndroidTrace>,
+}
+
+thread_local! {
+    /// The total number of bytes allocated by the current thread.
+    static THREAD_ALLOCATED_BYTES: Cell<u64> = const { Cell::new(0) };
+    /// Whether the current thread is updating the counters of a [`TracingAllocator`].
+    static UPDATING_COUNTERS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The total number of bytes which have been allocated by the current thread, through any
+/// [`TracingAllocator`].
+///
+/// This only increases, so the bytes allocated between two points can be found by subtracting
+/// the earlier value from the later one.
+/// Reallocations which grow an allocation count the additional bytes.
+///
+/// If no `TracingAllocator` is in use as the global allocator, this is always zero.
+pub fn thread_allocated_bytes() -> u64 {
+    THREAD_ALLOCATED_BYTES
+        .try_with(Cell::get)
+        .unwrap_or_default()
+}
+
+fn add_thread_allocated_bytes(bytes: usize) {
+    // This doesn't allocate, as the thread local has a const initialiser and no destructor
+    let _ = THREAD_ALLOCATED_BYTES.try_with(|total| {
+        total.set(total.get().saturating_add(bytes as u64));
+    });
+}
+
+impl<A> TracingAllocator<A> {
+    /// Wrap `inner`, such as [`std::alloc::System`].
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            update_interval: Duration::from_millis(10),
+            live_bytes: AtomicUsize::new(0),
+            live_allocations: AtomicUsize::new(0),
+            last_update: AtomicU64::new(0),
+            epoch: OnceLock::new(),
+            trace: OnceLock::new(),
+        }
+    }
+
+    /// Set the minimum time between updates of the counters.
+    #[must_use]
+    pub const fn with_update_interval(mut self, update_interval: Duration) -> Self {
+        self.update_interval = update_interval;
+        self
+    }
+
+    /// The number of bytes currently allocated through this allocator.
+    pub fn live_bytes(&self) -> usize {
+        self.live_bytes.load(Ordering::Relaxed)
+    }
+
+    /// The number of allocations made through this allocator which have not yet been freed.
+    pub fn live_allocations(&self) -> usize {
+        self.live_allocations.load(Ordering::Relaxed)
+    }
+
+    fn allocated(&self, size: usize) {
+        self.live_bytes.fetch_add(size, Ordering::Relaxed);
+        self.live_allocations.fetch_add(1, Ordering::Relaxed);
+        add_thread_allocated_bytes(size);
+        self.maybe_update_counters();
+    }
+
+    fn freed(&self, size: usize) {
+        self.live_bytes.fetch_sub(size, Ordering::Relaxed);
+        self.live_allocations.fetch_sub(1, Ordering::Relaxed);
+        self.maybe_update_counters();
+    }
+
+    /// Update the counters if the update interval has elapsed.
+    ///
+    /// This must not allocate, as it is called from inside the allocator.
+    fn maybe_update_counters(&self) {
+        let elapsed = self.epoch.get_or_init(Instant::now).elapsed();
+        let now = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        let last_update = self.last_update.load(Ordering::Relaxed);
+        if Duration::from_nanos(now.saturating_sub(last_update)) < self.update_interval {
+            return;
+        }
+        // Only one thread updates the counters for each interval
+        if self
+            .last_update
+            .compare_exchange(last_update, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        // Creating the `AndroidTrace` can allocate, which would re-enter this method whilst
+        // `self.trace` is being initialised, and so deadlock
+        let reentered = UPDATING_COUNTERS
+            .try_with(|updating| updating.replace(true))
+            .unwrap_or(true);
+        if reentered {
+            return;
+        }
+        self.update_counters();
+        let _ = UPDATING_COUNTERS.try_with(|updating| updating.set(false));
+    }
+
+    fn update_counters(&self) {
+        let trace = self.trace.get_or_init(AndroidTrace::new);
+        if !trace.is_enabled().unwrap_or(false) {
+            return;
+        }
+        let to_counter = |value: usize| i64::try_from(value).unwrap_or(i64::MAX);
+        trace.set_counter(c"heap.live_bytes", to_counter(self.live_bytes()));
+        trace.set_counter(c"heap.allocs", to_counter(self.live_allocations()));
+    }
+}
+
+// Safety: Every method forwards to `inner` with the same arguments, and returns its result unchanged
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TracingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Safety: The caller upholds the contract of `GlobalAlloc::alloc`
+        let ptr = unsafe { self.inner.alloc(layout) };
+        if !ptr.is_null() {
+            self.allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        // Safety: The caller upholds the contract of `GlobalAlloc::alloc_zeroed`
+        let ptr = unsafe { self.inner.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            self.allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Safety: The caller upholds the contract of `GlobalAlloc::dealloc`
+        unsafe { self.inner.dealloc(ptr, layout) };
+        self.freed(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // Safety: The caller upholds the contract of `GlobalAlloc::realloc`
+        let new_ptr = unsafe { self.inner.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            let old_size = layout.size();
+            if new_size >= old_size {
+                self.live_bytes
+                    .fetch_add(new_size - old_size, Ordering::Relaxed);
+                add_thread_allocated_bytes(new_size - old_size);
+            } else {
+                self.live_bytes
+                    .fetch_sub(old_size - new_size, Ordering::Relaxed);
+            }
+            self.maybe_update_counters();
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    use super::{thread_allocated_bytes, TracingAllocator};
+
+    #[test]
+    fn usage_is_tracked() {
+        let allocator = TracingAllocator::new(System);
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let before = thread_allocated_bytes();
+        // Safety: `layout` has a non-zero size, and each pointer is freed with the layout it was allocated with
+        unsafe {
+            let first = allocator.alloc(layout);
+            let second = allocator.alloc_zeroed(layout);
+            assert_eq!(
+                allocator.live_bytes(),
+                128,
+                "Both allocations should be counted as live bytes"
+            );
+            assert_eq!(
+                allocator.live_allocations(),
+                2,
+                "Both allocations should be counted"
+            );
+
+            let second = allocator.realloc(second, layout, 96);
+            assert_eq!(
+                allocator.live_bytes(),
+                160,
+                "Growing an allocation should add the extra bytes"
+            );
+            assert_eq!(
+                allocator.live_allocations(),
+                2,
+                "Reallocating shouldn't change the number of allocations"
+            );
+
+            allocator.dealloc(first, layout);
+            allocator.dealloc(second, Layout::from_size_align(96, 8).unwrap());
+        }
+        assert_eq!(
+            allocator.live_bytes(),
+            0,
+            "Freed allocations shouldn't be counted as live bytes"
+        );
+        assert_eq!(
+            allocator.live_allocations(),
+            0,
+            "Freed allocations shouldn't be counted"
+        );
+        assert_eq!(
+            thread_allocated_bytes() - before,
+            160,
+            "Freeing memory shouldn't reduce the thread's total"
+        );
+    }
+}
@@ -0,0 +1,467 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Channels which record their queue depth using Android Trace.
+//!
+//! See [`ChannelTracing`] for details.
+
+use std::{
+    ffi::CString,
+    fmt::{self, Debug},
+    sync::{
+        atomic::{AtomicI64, AtomicU8, Ordering},
+        mpsc::{self, RecvError, RecvTimeoutError, SendError, TryRecvError, TrySendError},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{cookie::CookieAllocator, AndroidTrace};
+
+/// Configures the tracing of a channel.
+///
+/// The number of messages which have been sent but not yet received is recorded as the
+/// `channel depth: {name}` counter, which is updated on every send and receive.
+///
+/// Optionally, the time each message spends in the channel can be recorded as a `channel wait: {name}`
+/// async section (using [`with_wait_sections`](Self::with_wait_sections)).
+/// This requires Android API level 29.
+///
+/// ## Usage
+///
+/// ```no_run
+/// use android_trace::channel::{traced_channel, ChannelTracing};
+///
+/// let (sender, receiver) = traced_channel::<u32>("decoded frames");
+/// sender.send(1).unwrap();
+/// assert_eq!(receiver.recv(), Ok(1));
+///
+/// let (sender, receiver) = ChannelTracing::new("render commands")
+///     .with_wait_sections(true)
+///     .sync_channel::<u32>(4);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChannelTracing {
+    trace: AndroidTrace,
+    name: String,
+    wait_sections: bool,
+}
+
+/// Create an unbounded channel which records its queue depth.
+///
+/// See [`ChannelTracing`] for details.
+pub fn traced_channel<T>(name: &str) -> (TracedSender<T>, TracedReceiver<T>) {
+    ChannelTracing::new(name).channel()
+}
+
+/// Create a bounded channel which records its queue depth.
+///
+/// See [`ChannelTracing`] for details.
+pub fn traced_sync_channel<T>(
+    name: &str,
+    bound: usize,
+) -> (TracedSyncSender<T>, TracedReceiver<T>) {
+    ChannelTracing::new(name).sync_channel(bound)
+}
+
+impl ChannelTracing {
+    /// Trace a channel, whose counter and sections are named after `name`.
+    pub fn new(name: &str) -> Self {
+        Self {
+            trace: AndroidTrace::new(),
+            name: name.to_owned(),
+            wait_sections: false,
+        }
+    }
+
+    /// Use `trace` rather than a new [`AndroidTrace`].
+    #[must_use]
+    pub fn with_trace(mut self, trace: AndroidTrace) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Whether to record the time each message spends in the channel as an async section.
+    #[must_use]
+    pub fn with_wait_sections(mut self, wait_sections: bool) -> Self {
+        self.wait_sections = wait_sections;
+        self
+    }
+
+    /// Create an unbounded channel, using [`std::sync::mpsc::channel`].
+    pub fn channel<T>(self) -> (TracedSender<T>, TracedReceiver<T>) {
+        let shared = self.shared();
+        let (sender, receiver) = mpsc::channel();
+        (
+            TracedSender {
+                inner: sender,
+                shared,
+            },
+            TracedReceiver { inner: receiver },
+        )
+    }
+
+    /// Create a bounded channel, using [`std::sync::mpsc::sync_channel`].
+    pub fn sync_channel<T>(self, bound: usize) -> (TracedSyncSender<T>, TracedReceiver<T>) {
+        let shared = self.shared();
+        let (sender, receiver) = mpsc::sync_channel(bound);
+        (
+            TracedSyncSender {
+                inner: sender,
+                shared,
+            },
+            TracedReceiver { inner: receiver },
+        )
+    }
+
+    fn shared(self) -> Arc<Shared> {
+        let name = self.name.replace('\0', "\\0");
+        let c_string = |prefix: &str| CString::new(format!("{prefix}: {name}")).unwrap_or_default();
+        Arc::new(Shared {
+            depth_name: c_string("channel depth"),
+            wait_name: c_string("channel wait"),
+            trace: self.trace,
+            wait_sections: self.wait_sections,
+            depth: AtomicI64::new(0),
+        })
+    }
+}
+
+/// The state shared by both ends of a channel.
+struct Shared {
+    trace: AndroidTrace,
+    depth_name: CString,
+    wait_name: CString,
+    wait_sections: bool,
+    depth: AtomicI64,
+}
+
+impl Shared {
+    fn update_depth(&self, delta: i64) {
+        let depth = self.depth.fetch_add(delta, Ordering::Relaxed) + delta;
+        if self.trace.is_enabled().unwrap_or(false) {
+            // A message can be received before its sender has counted it, which briefly
+            // makes the depth negative
+            self.trace.set_counter(&self.depth_name, depth.max(0));
+        }
+    }
+
+    /// Send `value` using `send`, only recording it once it has been sent.
+    ///
+    /// If sending fails, the envelope in the error must be [rejected](Envelope::reject).
+    fn send<T, E>(
+        self: &Arc<Self>,
+        value: T,
+        send: impl FnOnce(Envelope<T>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let wait = (self.wait_sections
+            && self.trace.could_use_api_level_29()
+            && self.trace.is_enabled().unwrap_or(false))
+        .then(|| {
+            Arc::new(WaitSection {
+                cookie: CookieAllocator::global().allocate(&self.wait_name),
+                state: AtomicU8::new(WaitSection::PENDING),
+            })
+        });
+        let envelope = Envelope {
+            value,
+            token: Token {
+                shared: Arc::clone(self),
+                wait: wait.clone(),
+                rejected: false,
+            },
+        };
+        send(envelope)?;
+        self.update_depth(1);
+        if let Some(wait) = wait {
+            self.trace.begin_async_section(&self.wait_name, wait.cookie);
+            if wait.state.swap(WaitSection::BEGUN, Ordering::AcqRel) == WaitSection::RECEIVED {
+                self.end_wait(&wait);
+            }
+        }
+        Ok(())
+    }
+
+    fn end_wait(&self, wait: &WaitSection) {
+        self.trace.end_async_section(&self.wait_name, wait.cookie);
+        CookieAllocator::global().free(&self.wait_name, wait.cookie);
+    }
+}
+
+/// The wait section of a message.
+///
+/// A message can be received before its sender has begun this section, so the section is
+/// ended by whichever of the sender and the receiver is last.
+struct WaitSection {
+    cookie: i32,
+    state: AtomicU8,
+}
+
+impl WaitSection {
+    const PENDING: u8 = 0;
+    const BEGUN: u8 = 1;
+    const RECEIVED: u8 = 2;
+}
+
+impl Debug for Shared {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Shared")
+            .field("depth_name", &self.depth_name)
+            .field("wait_sections", &self.wait_sections)
+            .field("depth", &self.depth)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A message in a traced channel.
+struct Envelope<T> {
+    value: T,
+    token: Token,
+}
+
+impl<T> Envelope<T> {
+    /// Take the message out of the channel, which ends its wait section.
+    fn open(self) -> T {
+        self.value
+    }
+
+    /// Take back a message which couldn't be sent, without recording anything.
+    fn reject(self) -> T {
+        let Self { value, mut token } = self;
+        token.rejected = true;
+        if let Some(wait) = &token.wait {
+            CookieAllocator::global().free(&token.shared.wait_name, wait.cookie);
+        }
+        value
+    }
+}
+
+/// Removes a message from the queue depth, and ends its wait section, when dropped.
+///
+/// This happens when the message is received, or when it is dropped because the receiver was.
+struct Token {
+    shared: Arc<Shared>,
+    wait: Option<Arc<WaitSection>>,
+    /// Whether the message was never sent, so was never recorded.
+    rejected: bool,
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        if self.rejected {
+            return;
+        }
+        let shared = &self.shared;
+        if let Some(wait) = &self.wait {
+            if wait.state.swap(WaitSection::RECEIVED, Ordering::AcqRel) == WaitSection::BEGUN {
+                shared.end_wait(wait);
+            }
+        }
+        shared.update_depth(-1);
+    }
+}
+
+/// The sending half of a channel created by [`ChannelTracing::channel`].
+///
+/// See [`std::sync::mpsc::Sender`].
+#[derive(Debug)]
+pub struct TracedSender<T> {
+    inner: mpsc::Sender<Envelope<T>>,
+    shared: Arc<Shared>,
+}
+
+impl<T> TracedSender<T> {
+    /// Send a message on this channel.
+    ///
+    /// See [`std::sync::mpsc::Sender::send`].
+    ///
+    /// # Errors
+    ///
+    /// If the receiver has been dropped, returning the message.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.shared
+            .send(value, |envelope| self.inner.send(envelope))
+            .map_err(|SendError(envelope)| SendError(envelope.reject()))
+    }
+}
+
+impl<T> Clone for TracedSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+/// The sending half of a channel created by [`ChannelTracing::sync_channel`].
+///
+/// See [`std::sync::mpsc::SyncSender`].
+#[derive(Debug)]
+pub struct TracedSyncSender<T> {
+    inner: mpsc::SyncSender<Envelope<T>>,
+    shared: Arc<Shared>,
+}
+
+impl<T> TracedSyncSender<T> {
+    /// Send a message on this channel, blocking until there is space in the buffer.
+    ///
+    /// See [`std::sync::mpsc::SyncSender::send`].
+    ///
+    /// # Errors
+    ///
+    /// If the receiver has been dropped, returning the message.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.shared
+            .send(value, |envelope| self.inner.send(envelope))
+            .map_err(|SendError(envelope)| SendError(envelope.reject()))
+    }
+
+    /// Attempt to send a message on this channel without blocking.
+    ///
+    /// See [`std::sync::mpsc::SyncSender::try_send`].
+    ///
+    /// # Errors
+    ///
+    /// If the buffer is full or the receiver has been dropped, returning the message.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.shared
+            .send(value, |envelope| self.inner.try_send(envelope))
+            .map_err(|error| match error {
+                TrySendError::Full(envelope) => TrySendError::Full(envelope.reject()),
+                TrySendError::Disconnected(envelope) => {
+                    TrySendError::Disconnected(envelope.reject())
+                }
+            })
+    }
+}
+
+impl<T> Clone for TracedSyncSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+/// The receiving half of a channel created by [`ChannelTracing`].
+///
+/// See [`std::sync::mpsc::Receiver`].
+#[derive(Debug)]
+pub struct TracedReceiver<T> {
+    inner: mpsc::Receiver<Envelope<T>>,
+}
+
+impl<T> TracedReceiver<T> {
+    /// Block until a message is received.
+    ///
+    /// See [`std::sync::mpsc::Receiver::recv`].
+    ///
+    /// # Errors
+    ///
+    /// If every sender has been dropped and the channel is empty.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.inner.recv().map(Envelope::open)
+    }
+
+    /// Attempt to receive a message without blocking.
+    ///
+    /// See [`std::sync::mpsc::Receiver::try_recv`].
+    ///
+    /// # Errors
+    ///
+    /// If the channel is empty, or every sender has been dropped.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.inner.try_recv().map(Envelope::open)
+    }
+
+    /// Block until a message is received, or `timeout` has elapsed.
+    ///
+    /// See [`std::sync::mpsc::Receiver::recv_timeout`].
+    ///
+    /// # Errors
+    ///
+    /// If the timeout elapsed, or every sender has been dropped and the channel is empty.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.inner.recv_timeout(timeout).map(Envelope::open)
+    }
+
+    /// An iterator which blocks waiting for messages, until every sender has been dropped.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        self.inner.iter().map(Envelope::open)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::traced_sync_channel;
+
+    #[test]
+    #[cfg(all(feature = "api_level_29", not(target_os = "android")))]
+    fn only_sent_messages_are_recorded() {
+        use super::ChannelTracing;
+        use crate::recording::{self, Event};
+
+        recording::set_enabled(true);
+        let (sender, receiver) = ChannelTracing::new("test")
+            .with_wait_sections(true)
+            .sync_channel(1);
+        sender.send(1).unwrap();
+        assert!(sender.try_send(2).is_err(), "The channel should be full");
+        assert_eq!(
+            receiver.recv(),
+            Ok(1),
+            "The sent message should be received"
+        );
+        drop(receiver);
+        assert!(
+            sender.send(3).is_err(),
+            "The channel should be disconnected"
+        );
+        let events = recording::take_events();
+        let [_, Event::BeginAsync(_, cookie), ..] = events[..] else {
+            panic!("The sent message should begin a wait section, got {events:?}");
+        };
+        assert_eq!(
+            events,
+            [
+                Event::Counter("channel depth: test".into(), 1),
+                Event::BeginAsync("channel wait: test".into(), cookie),
+                Event::EndAsync("channel wait: test".into(), cookie),
+                Event::Counter("channel depth: test".into(), 0),
+            ],
+            "Messages which weren't sent shouldn't be counted or have a wait section"
+        );
+    }
+
+    #[test]
+    fn depth_is_tracked() {
+        let (sender, receiver) = traced_sync_channel("test", 2);
+        sender.send(1).unwrap();
+        sender.send(2).unwrap();
+        assert!(sender.try_send(3).is_err(), "The channel should be full");
+        assert_eq!(
+            sender
+                .shared
+                .depth
+                .load(std::sync::atomic::Ordering::Relaxed),
+            2,
+            "Rejected messages shouldn't be counted"
+        );
+        assert_eq!(
+            receiver.recv(),
+            Ok(1),
+            "Messages should be received in order"
+        );
+        drop(receiver);
+        assert_eq!(
+            sender
+                .shared
+                .depth
+                .load(std::sync::atomic::Ordering::Relaxed),
+            0,
+            "Messages dropped with the receiver should be removed"
+        );
+    }
+}
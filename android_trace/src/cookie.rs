@@ -0,0 +1,137 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Allocation of cookies for async sections.
+
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    sync::{Mutex, OnceLock, PoisonError},
+};
+
+/// Hands out cookies for [`AndroidTrace::begin_async_section`](crate::AndroidTrace::begin_async_section).
+///
+/// Android pairs async begin and end calls by their section name and cookie, so
+/// two live sections with the same name must never share a cookie.
+/// Cookies are tracked per name, and a cookie is only handed out again once it has been freed.
+///
+/// Code which records async sections should generally use the [global](Self::global) allocator,
+/// so that it doesn't hand out the same cookies as other code recording sections with the same name.
+///
+/// ```no_run
+/// use android_trace::{cookie::CookieAllocator, AndroidTrace};
+///
+/// let trace = AndroidTrace::new();
+/// let cookies = CookieAllocator::global();
+/// let cookie = cookies.allocate(c"Download");
+/// trace.begin_async_section(c"Download", cookie);
+/// // ...
+/// trace.end_async_section(c"Download", cookie);
+/// cookies.free(c"Download", cookie);
+/// ```
+#[derive(Debug, Default)]
+pub struct CookieAllocator {
+    names: Mutex<HashMap<CString, NameCookies>>,
+}
+
+#[derive(Debug, Default)]
+struct NameCookies {
+    /// The lowest cookie which has never been handed out for this name.
+    next: i32,
+    /// Cookies which were handed out, but have since been freed.
+    free: Vec<i32>,
+    /// The number of cookies currently in use.
+    live: usize,
+}
+
+impl CookieAllocator {
+    /// Create a `CookieAllocator`, which is independent of the global allocator.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The allocator shared by all code recording async sections in this process,
+    /// including [`TraceFutureExt`](crate::future::TraceFutureExt) and the layers in `tracing_android_trace`.
+    ///
+    /// Different code can record sections with the same name, so they must not hand out
+    /// cookies independently.
+    pub fn global() -> &'static Self {
+        static GLOBAL: OnceLock<CookieAllocator> = OnceLock::new();
+        GLOBAL.get_or_init(Self::new)
+    }
+
+    /// Get a cookie which is not used by any live section named `name`.
+    ///
+    /// This cookie should be returned using [`Self::free`] once the section has ended.
+    pub fn allocate(&self, name: &CStr) -> i32 {
+        let mut names = self.names.lock().unwrap_or_else(PoisonError::into_inner);
+        let cookies = match names.get_mut(name) {
+            Some(cookies) => cookies,
+            None => names.entry(name.to_owned()).or_default(),
+        };
+        cookies.live += 1;
+        if let Some(cookie) = cookies.free.pop() {
+            cookie
+        } else {
+            let cookie = cookies.next;
+            // We would need more than i32::MAX simultaneously live sections with the same
+            // name for this to wrap, at which point the trace is meaningless anyway.
+            cookies.next = cookies.next.wrapping_add(1);
+            cookie
+        }
+    }
+
+    /// Return a cookie previously handed out by [`Self::allocate`] for `name`.
+    pub fn free(&self, name: &CStr, cookie: i32) {
+        let mut names = self.names.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(cookies) = names.get_mut(name) else {
+            debug_assert!(false, "Freed a cookie for a name which has no live cookies");
+            return;
+        };
+        cookies.live -= 1;
+        if cookies.live == 0 {
+            // Don't keep names around once they have no live sections, so that this
+            // map doesn't grow without bound if section names include field values.
+            names.remove(name);
+        } else {
+            cookies.free.push(cookie);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn live_cookies_are_unique() {
+        let allocator = CookieAllocator::new();
+        let a = allocator.allocate(c"a");
+        let b = allocator.allocate(c"a");
+        assert_ne!(
+            a, b,
+            "Two live sections with the same name got the same cookie"
+        );
+        allocator.free(c"a", a);
+        let c = allocator.allocate(c"a");
+        assert_ne!(
+            b, c,
+            "A freed cookie was reused while another was still live"
+        );
+        assert_eq!(a, c, "Freed cookies should be reused");
+    }
+
+    #[test]
+    fn names_are_independent() {
+        let allocator = CookieAllocator::new();
+        let a = allocator.allocate(c"a");
+        let b = allocator.allocate(c"b");
+        assert_eq!(a, b, "Cookies are allocated per name");
+        allocator.free(c"a", a);
+        allocator.free(c"b", b);
+        assert!(
+            allocator.names.lock().unwrap().is_empty(),
+            "Names without live cookies should be removed"
+        );
+    }
+}
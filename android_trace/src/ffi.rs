@@ -4,7 +4,7 @@
 #[cfg(not(all(feature = "api_level_23", feature = "api_level_29")))]
 use core::{ffi::CStr, mem};
 
-use libc::c_char;
+use libc::{c_char, c_int};
 
 /// # Safety
 ///
@@ -31,7 +31,7 @@ unsafe fn transmute_if_not_null<F>(func: *mut libc::c_void) -> Option<F> {
 }
 
 #[link(name = "android", kind = "dylib")]
-#[cfg(target_os = "android")]
+#[cfg(all(target_os = "android", not(feature = "recording")))]
 #[cfg(feature = "api_level_23")]
 extern "C" {
     #[link_name = "ATrace_beginSection"]
@@ -58,6 +58,7 @@ pub(crate) struct ATraceAPILevel23Methods {
 // SAFETY: This is required for the calls to dlsym to be safe, ensuring that the accessed methods
 // don't get unlinked
 #[link(name = "android", kind = "dylib")]
+#[cfg(target_os = "android")]
 extern "C" {}
 
 #[cfg(not(feature = "api_level_23"))]
@@ -96,7 +97,7 @@ impl ATraceAPILevel23Methods {
 }
 
 #[link(name = "android", kind = "dylib")]
-#[cfg(target_os = "android")]
+#[cfg(all(target_os = "android", not(feature = "recording")))]
 #[cfg(feature = "api_level_29")]
 extern "C" {
     #[link_name = "ATrace_beginAsyncSection"]
@@ -153,3 +154,104 @@ impl ATraceAPILevel29Methods {
             .as_ref()
     }
 }
+
+#[link(name = "log", kind = "dylib")]
+#[cfg(target_os = "android")]
+extern "C" {
+    #[link_name = "__android_log_write"]
+    /// <https://developer.android.com/ndk/reference/group/logging#__android_log_write>
+    pub(crate) fn android_log_write_raw(
+        priority: c_int,
+        tag: *const c_char,
+        text: *const c_char,
+    ) -> c_int;
+
+    #[link_name = "__android_log_buf_write"]
+    /// <https://developer.android.com/ndk/reference/group/logging#__android_log_buf_write>
+    pub(crate) fn android_log_buf_write_raw(
+        buffer_id: c_int,
+        priority: c_int,
+        tag: *const c_char,
+        text: *const c_char,
+    ) -> c_int;
+}
+
+/// Stand-ins for the tracing functions above, which record the calls made whilst tracing is
+/// enabled, for the `recording` feature and the unit tests on the host.
+#[cfg(any(
+    feature = "recording",
+    all(test, not(target_os = "android"), feature = "api_level_23")
+))]
+mod recording {
+    use core::ffi::CStr;
+
+    use super::c_char;
+    use crate::recording::{name, record, Event};
+
+    pub(crate) unsafe fn atrace_begin_section_raw(section_name: *const c_char) {
+        // Safety: The caller passes a valid C string
+        let section_name = unsafe { CStr::from_ptr(section_name) };
+        record(|| Event::Begin(name(section_name)));
+    }
+
+    pub(crate) unsafe fn atrace_end_section_raw() {
+        record(|| Event::End);
+    }
+
+    pub(crate) unsafe fn atrace_is_enabled_raw() -> bool {
+        crate::recording::is_enabled()
+    }
+
+    #[cfg(feature = "api_level_29")]
+    pub(crate) unsafe fn atrace_begin_async_section_raw(section_name: *const c_char, cookie: i32) {
+        // Safety: The caller passes a valid C string
+        let section_name = unsafe { CStr::from_ptr(section_name) };
+        record(|| Event::BeginAsync(name(section_name), cookie));
+    }
+
+    #[cfg(feature = "api_level_29")]
+    pub(crate) unsafe fn atrace_end_async_section_raw(section_name: *const c_char, cookie: i32) {
+        // Safety: The caller passes a valid C string
+        let section_name = unsafe { CStr::from_ptr(section_name) };
+        record(|| Event::EndAsync(name(section_name), cookie));
+    }
+
+    #[cfg(feature = "api_level_29")]
+    pub(crate) unsafe fn atrace_set_counter_raw(counter_name: *const c_char, counter_value: i64) {
+        // Safety: The caller passes a valid C string
+        let counter_name = unsafe { CStr::from_ptr(counter_name) };
+        record(|| Event::Counter(name(counter_name), counter_value));
+    }
+}
+
+#[cfg(any(
+    feature = "recording",
+    all(test, not(target_os = "android"), feature = "api_level_23")
+))]
+pub(crate) use recording::*;
+
+/// Stand-ins for the logging functions above, so that this crate can be built on the host.
+#[cfg(all(not(target_os = "android"), any(feature = "recording", test)))]
+mod host_log {
+    use super::{c_char, c_int};
+
+    pub(crate) unsafe fn android_log_write_raw(
+        _priority: c_int,
+        _tag: *const c_char,
+        _text: *const c_char,
+    ) -> c_int {
+        0
+    }
+
+    pub(crate) unsafe fn android_log_buf_write_raw(
+        _buffer_id: c_int,
+        _priority: c_int,
+        _tag: *const c_char,
+        _text: *const c_char,
+    ) -> c_int {
+        0
+    }
+}
+
+#[cfg(all(not(target_os = "android"), any(feature = "recording", test)))]
+pub(crate) use host_log::*;
@@ -0,0 +1,216 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Recording the lifetime of a [`Future`] as an async section.
+//!
+//! See [`TraceFutureExt`] for details.
+
+use std::{
+    borrow::Cow,
+    ffi::CStr,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use pin_project_lite::pin_project;
+
+use crate::{cookie::CookieAllocator, AndroidTrace};
+
+/// An extension trait for recording the lifetime of a [`Future`] using Android Trace,
+/// without needing `tracing`.
+///
+/// ## Usage
+///
+/// ```no_run
+/// use android_trace::future::TraceFutureExt;
+///
+/// async fn download(url: &str) -> Vec<u8> {
+///     // ...
+/// #   Vec::new()
+/// }
+///
+/// # async fn example() {
+/// let bytes = download("https://example.com")
+///     .atrace(c"Download")
+///     .with_poll_sections(true)
+///     .await;
+/// # }
+/// ```
+pub trait TraceFutureExt: Future + Sized {
+    /// Record an async section named `name` from when this future is first polled until it completes
+    /// or is dropped.
+    ///
+    /// The section's cookie is allocated using the [global](CookieAllocator::global) cookie allocator.
+    /// This requires Android API level 29; if that is not available, only the sections for each poll
+    /// (if enabled using [`TracedFuture::with_poll_sections`]) are recorded.
+    fn atrace(self, name: impl Into<Cow<'static, CStr>>) -> TracedFuture<Self> {
+        TracedFuture {
+            future: self,
+            section: Section {
+                trace: AndroidTrace::new(),
+                name: name.into(),
+                cookie: None,
+                poll_sections: false,
+            },
+        }
+    }
+}
+
+impl<F: Future> TraceFutureExt for F {}
+
+pin_project! {
+    /// A future which records its lifetime as an async section.
+    ///
+    /// Created using [`TraceFutureExt::atrace`].
+    #[derive(Debug)]
+    #[must_use = "Futures do nothing unless you `.await` or poll them"]
+    pub struct TracedFuture<F> {
+        #[pin]
+        future: F,
+        section: Section,
+    }
+
+    impl<F> PinnedDrop for TracedFuture<F> {
+        fn drop(this: Pin<&mut Self>) {
+            this.project().section.end();
+        }
+    }
+}
+
+/// The state of the section of a [`TracedFuture`], which isn't pinned.
+#[derive(Debug)]
+struct Section {
+    trace: AndroidTrace,
+    name: Cow<'static, CStr>,
+    /// The cookie of the async section, if it has begun and not yet ended.
+    cookie: Option<i32>,
+    poll_sections: bool,
+}
+
+impl<F> TracedFuture<F> {
+    /// Whether to also record a sync section with the same name around each poll of the future.
+    ///
+    /// This shows which thread the future was running on, and for how long.
+    pub fn with_poll_sections(mut self, poll_sections: bool) -> Self {
+        self.section.poll_sections = poll_sections;
+        self
+    }
+
+    /// Use `trace` rather than a new [`AndroidTrace`].
+    pub fn with_trace(mut self, trace: AndroidTrace) -> Self {
+        self.section.trace = trace;
+        self
+    }
+}
+
+impl Section {
+    fn begin(&mut self) {
+        if self.cookie.is_none()
+            && self.trace.could_use_api_level_29()
+            && self.trace.is_enabled().unwrap_or(false)
+        {
+            let cookie = CookieAllocator::global().allocate(&self.name);
+            self.trace.begin_async_section(&self.name, cookie);
+            self.cookie = Some(cookie);
+        }
+    }
+
+    fn end(&mut self) {
+        if let Some(cookie) = self.cookie.take() {
+            self.trace.end_async_section(&self.name, cookie);
+            CookieAllocator::global().free(&self.name, cookie);
+        }
+    }
+}
+
+/// Ends the sync section around a poll when dropped, so that it is ended even if the poll panics.
+struct PollSection<'a> {
+    /// The trace to end the section on, if the section was begun.
+    trace: Option<&'a AndroidTrace>,
+}
+
+impl<'a> PollSection<'a> {
+    fn begin(section: &'a Section) -> Self {
+        if !section.poll_sections || !section.trace.is_enabled().unwrap_or(false) {
+            return Self { trace: None };
+        }
+        section.trace.begin_section(&section.name);
+        Self {
+            trace: Some(&section.trace),
+        }
+    }
+}
+
+impl Drop for PollSection<'_> {
+    fn drop(&mut self) {
+        if let Some(trace) = self.trace {
+            trace.end_section();
+        }
+    }
+}
+
+impl<F: Future> Future for TracedFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let section = this.section;
+        section.begin();
+        let poll_section = PollSection::begin(section);
+        let result = this.future.poll(cx);
+        drop(poll_section);
+        if result.is_ready() {
+            section.end();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    #[cfg(all(feature = "api_level_29", not(target_os = "android")))]
+    fn poll_section_ends_on_panic() {
+        use std::{
+            future::{poll_fn, Future},
+            panic::{catch_unwind, AssertUnwindSafe},
+            pin::pin,
+            sync::Arc,
+            task::{Context, Wake, Waker},
+        };
+
+        use super::TraceFutureExt;
+        use crate::recording::{self, Event};
+
+        struct NoopWaker;
+        impl Wake for NoopWaker {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        recording::set_enabled(true);
+        let mut future = pin!(
+            poll_fn(|_| -> std::task::Poll<()> { panic!("poll failed") })
+                .atrace(c"panics")
+                .with_poll_sections(true)
+        );
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            future.as_mut().poll(&mut Context::from_waker(&waker))
+        }));
+        assert!(result.is_err(), "The inner future should have panicked");
+        let events = recording::take_events();
+        let [Event::BeginAsync(_, cookie), ..] = events[..] else {
+            panic!("Polling should begin the async section, got {events:?}");
+        };
+        assert_eq!(
+            events,
+            [
+                Event::BeginAsync("panics".into(), cookie),
+                Event::Begin("panics".into()),
+                Event::End,
+            ],
+            "The poll section should be ended when the poll panics"
+        );
+    }
+}
@@ -4,6 +4,13 @@
 // https://linebender.org/blog/doc-include
 //! [AndroidTrace]: crate::AndroidTrace
 //! [dlsym]: libc::dlsym
+//! [atrace]: crate::future::TraceFutureExt::atrace
+//! [sync]: crate::sync
+//! [channel]: crate::channel
+//! [TracingAllocator]: crate::allocator::TracingAllocator
+//! [ProcSampler]: crate::proc_sampler::ProcSampler
+//! [scope]: crate::scope
+//! [function_scope]: crate::function_scope
 // File links are not supported by rustdoc
 //! [LICENSE-APACHE]: https://github.com/linebender/android_trace/blob/main/LICENSE-APACHE
 //! [LICENSE-MIT]: https://github.com/linebender/android_trace/blob/main/LICENSE-MIT
@@ -33,14 +40,36 @@ use ffi::ATraceAPILevel29Methods;
 use core::ffi::CStr;
 use std::fmt::Debug;
 
-#[cfg(not(target_os = "android"))]
+// The unit tests, and the tests of crates which use the `recording` feature, can run on the host
+#[cfg(not(any(target_os = "android", test, feature = "recording")))]
 compile_error!(
     r#"android_trace only supports Android. If you are depending on it, ensure that it is within
     [target.'cfg(target_os = "android")'.dependencies]
     in your Cargo.toml"#
 );
 
+pub mod allocator;
+pub mod channel;
+pub mod cookie;
 mod ffi;
+pub mod future;
+#[cfg(feature = "log")]
+pub mod log_android_trace;
+pub mod logcat;
+#[cfg(feature = "metrics")]
+pub mod metrics_recorder;
+pub mod proc_sampler;
+pub mod profiling;
+#[cfg(feature = "puffin")]
+pub mod puffin_bridge;
+#[cfg(any(
+    feature = "recording",
+    all(test, not(target_os = "android"), feature = "api_level_23")
+))]
+pub mod recording;
+pub mod sync;
+#[cfg(feature = "tokio")]
+pub mod tokio_runtime;
 
 /// A handle to the available NDK tracing functions
 ///
@@ -0,0 +1,204 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Support for recording messages from the [`log`] crate using Android Trace.
+//!
+//! See [`AndroidTraceLogger`] for details.
+
+use std::ffi::CString;
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use crate::AndroidTrace;
+
+/// A [`Log`] implementation which records each log record as an instant section
+/// (a section which is ended immediately after it begins).
+///
+/// This means that the output of crates which use `log` lines up with the spans or sections recorded
+/// on the same thread.
+/// The section is named `{target}: {message}`.
+///
+/// Records with the target chosen using [`with_counter_target`](Self::with_counter_target) and
+/// a message of the form `name=value` (where `value` is an integer) instead set the counter `name`
+/// to `value`, using [`AndroidTrace::set_counter`].
+///
+/// Records are also passed to an inner logger, if one is given using [`with_inner`](Self::with_inner).
+///
+/// ## Usage
+///
+/// ```no_run
+/// use android_trace::log_android_trace::AndroidTraceLogger;
+///
+/// AndroidTraceLogger::new()
+///     .with_max_level(log::LevelFilter::Debug)
+///     .with_counter_target("counters")
+///     .init()
+///     .unwrap();
+///
+/// log::info!("Loading assets");
+/// log::info!(target: "counters", "loaded_assets={}", 12);
+/// ```
+pub struct AndroidTraceLogger {
+    trace: AndroidTrace,
+    max_level: LevelFilter,
+    counter_target: Option<String>,
+    inner: Option<Box<dyn Log>>,
+}
+
+impl AndroidTraceLogger {
+    /// Create a `AndroidTraceLogger`, which records messages at the `Info` level and above.
+    pub fn new() -> Self {
+        Self::with_trace(AndroidTrace::new())
+    }
+
+    /// Create a `AndroidTraceLogger` from a pre-existing [`AndroidTrace`].
+    pub fn with_trace(trace: AndroidTrace) -> Self {
+        Self {
+            trace,
+            max_level: LevelFilter::Info,
+            counter_target: None,
+            inner: None,
+        }
+    }
+
+    /// Only record messages at `max_level` and above.
+    ///
+    /// This does not affect which messages are passed to the inner logger.
+    #[must_use]
+    pub fn with_max_level(mut self, max_level: LevelFilter) -> Self {
+        self.max_level = max_level;
+        self
+    }
+
+    /// Treat records with the given target and a message of the form `name=value` as counter values.
+    #[must_use]
+    pub fn with_counter_target(mut self, target: &str) -> Self {
+        self.counter_target = Some(target.to_owned());
+        self
+    }
+
+    /// Also pass every record to `inner`, such as a logger which writes to logcat.
+    #[must_use]
+    pub fn with_inner(mut self, inner: impl Log + 'static) -> Self {
+        self.inner = Some(Box::new(inner));
+        self
+    }
+
+    /// Set this as the global logger.
+    ///
+    /// If there is an inner logger, the maximum level is not limited, as the inner logger
+    /// might be interested in any record.
+    ///
+    /// # Errors
+    ///
+    /// If a global logger has already been set.
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        let max_level = if self.inner.is_some() {
+            LevelFilter::Trace
+        } else {
+            self.max_level
+        };
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+
+    fn should_trace(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= self.max_level
+    }
+
+    fn trace_record(&self, record: &Record<'_>) {
+        if !self.trace.is_enabled().unwrap_or(false) {
+            return;
+        }
+        let message = record.args().to_string();
+        if self.counter_target.as_deref() == Some(record.target()) {
+            if let Some((name, value)) = parse_counter(&message) {
+                if let Ok(name) = CString::new(name) {
+                    self.trace.set_counter(&name, value);
+                    return;
+                }
+            }
+        }
+        let name = format!("{}: {}", record.target(), message).replace('\0', "\\0");
+        if let Ok(name) = CString::new(name) {
+            self.trace.begin_section(&name);
+            self.trace.end_section();
+        }
+    }
+}
+
+/// Parse a message of the form `name=value`, where `value` is an integer.
+fn parse_counter(message: &str) -> Option<(&str, i64)> {
+    let (name, value) = message.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((name, value.trim().parse().ok()?))
+}
+
+impl Default for AndroidTraceLogger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for AndroidTraceLogger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AndroidTraceLogger")
+            .field("trace", &self.trace)
+            .field("max_level", &self.max_level)
+            .field("counter_target", &self.counter_target)
+            .field("inner", &self.inner.as_ref().map(|_| "<logger>"))
+            .finish()
+    }
+}
+
+impl Log for AndroidTraceLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.should_trace(metadata)
+            || self
+                .inner
+                .as_ref()
+                .is_some_and(|inner| inner.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if self.should_trace(record.metadata()) {
+            self.trace_record(record);
+        }
+        if let Some(inner) = &self.inner {
+            if inner.enabled(record.metadata()) {
+                inner.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(inner) = &self.inner {
+            inner.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_counter;
+
+    #[test]
+    fn counters_are_parsed() {
+        assert_eq!(
+            parse_counter("frames = 12"),
+            Some(("frames", 12)),
+            "Whitespace is ignored"
+        );
+        assert_eq!(
+            parse_counter("frames=twelve"),
+            None,
+            "Values must be integers"
+        );
+        assert_eq!(parse_counter("=12"), None, "Names must not be empty");
+        assert_eq!(parse_counter("no counter"), None, "An `=` is required");
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Writing messages to the Android log (logcat).
+//!
+//! These functions have been available since the first Android API level, so are always linked directly.
+
+use core::ffi::CStr;
+
+use libc::c_int;
+
+use crate::ffi;
+
+/// The priority of a log message.
+///
+/// Corresponds to [`android_LogPriority`](https://developer.android.com/ndk/reference/group/logging#android_logpriority).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum LogPriority {
+    /// `ANDROID_LOG_VERBOSE`
+    Verbose,
+    /// `ANDROID_LOG_DEBUG`
+    Debug,
+    /// `ANDROID_LOG_INFO`
+    Info,
+    /// `ANDROID_LOG_WARN`
+    Warn,
+    /// `ANDROID_LOG_ERROR`
+    Error,
+    /// `ANDROID_LOG_FATAL`
+    Fatal,
+}
+
+impl LogPriority {
+    fn raw(self) -> c_int {
+        match self {
+            Self::Verbose => 2,
+            Self::Debug => 3,
+            Self::Info => 4,
+            Self::Warn => 5,
+            Self::Error => 6,
+            Self::Fatal => 7,
+        }
+    }
+}
+
+/// A log buffer, which can be chosen using [`buf_write`].
+///
+/// Corresponds to [`log_id`](https://developer.android.com/ndk/reference/group/logging#log_id).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum LogBuffer {
+    /// `LOG_ID_MAIN`, the buffer used by [`write()`].
+    Main,
+    /// `LOG_ID_RADIO`
+    Radio,
+    /// `LOG_ID_EVENTS`
+    Events,
+    /// `LOG_ID_SYSTEM`
+    System,
+    /// `LOG_ID_CRASH`
+    Crash,
+}
+
+impl LogBuffer {
+    fn raw(self) -> c_int {
+        match self {
+            Self::Main => 0,
+            Self::Radio => 1,
+            Self::Events => 2,
+            Self::System => 3,
+            Self::Crash => 4,
+        }
+    }
+}
+
+/// Write `text` to the main log buffer, with the given priority and tag.
+///
+/// Calls [`__android_log_write`](https://developer.android.com/ndk/reference/group/logging#__android_log_write).
+#[doc(alias = "__android_log_write")]
+pub fn write(priority: LogPriority, tag: &CStr, text: &CStr) {
+    // Safety: tag and text are valid C strings
+    unsafe {
+        ffi::android_log_write_raw(priority.raw(), tag.as_ptr(), text.as_ptr());
+    }
+}
+
+/// Write `text` to the given log buffer, with the given priority and tag.
+///
+/// Calls [`__android_log_buf_write`](https://developer.android.com/ndk/reference/group/logging#__android_log_buf_write).
+#[doc(alias = "__android_log_buf_write")]
+pub fn buf_write(buffer: LogBuffer, priority: LogPriority, tag: &CStr, text: &CStr) {
+    // Safety: tag and text are valid C strings
+    unsafe {
+        ffi::android_log_buf_write_raw(buffer.raw(), priority.raw(), tag.as_ptr(), text.as_ptr());
+    }
+}
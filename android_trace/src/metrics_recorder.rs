@@ -0,0 +1,375 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Support for recording metrics from the [`metrics`] crate as Android Trace counters.
+//!
+//! See [`AndroidTraceRecorder`] for details.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::CString,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError, RwLock,
+    },
+};
+
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SetRecorderError, SharedString, Unit,
+};
+
+use crate::AndroidTrace;
+
+/// A [`Recorder`] which records each metric as an Android Trace counter, using
+/// [`AndroidTrace::set_counter`].
+///
+/// - Gauges set the counter to their current value.
+/// - Counters set the counter to their running total.
+/// - Histograms set the counter to each recorded sample, or to percentiles of the recent samples
+///   if [`with_histogram_percentiles`](Self::with_histogram_percentiles) is used.
+///
+/// Android Trace counters are integers, so the values of gauges and histograms are rounded to the nearest
+/// integer.
+/// Metrics which need more precision should be recorded using a smaller unit, such as microseconds
+/// rather than seconds.
+///
+/// The labels of a metric are included in the name of its counter, in the form
+/// `name{key=value,other_key=value}`.
+///
+/// ## Usage
+///
+/// ```no_run
+/// use android_trace::metrics_recorder::AndroidTraceRecorder;
+///
+/// AndroidTraceRecorder::new().install().unwrap();
+///
+/// metrics::gauge!("queue_length", "queue" => "decode").set(12);
+/// // Shown as the counter `queue_length{queue=decode}`
+/// ```
+#[derive(Debug)]
+pub struct AndroidTraceRecorder {
+    trace: AndroidTrace,
+    histogram_percentiles: Option<HistogramPercentiles>,
+    counters: Registry<Arc<CounterState>>,
+    gauges: Registry<Arc<GaugeState>>,
+    histograms: Registry<Arc<HistogramState>>,
+}
+
+#[derive(Debug, Clone)]
+struct HistogramPercentiles {
+    percentiles: Vec<u8>,
+    window: usize,
+}
+
+impl AndroidTraceRecorder {
+    /// Create a `AndroidTraceRecorder`.
+    pub fn new() -> Self {
+        Self::with_trace(AndroidTrace::new())
+    }
+
+    /// Create a `AndroidTraceRecorder` from a pre-existing [`AndroidTrace`].
+    pub fn with_trace(trace: AndroidTrace) -> Self {
+        Self {
+            trace,
+            histogram_percentiles: None,
+            counters: Registry::default(),
+            gauges: Registry::default(),
+            histograms: Registry::default(),
+        }
+    }
+
+    /// Record histograms as percentiles of their most recent `window` samples, rather than recording
+    /// each sample.
+    ///
+    /// Each percentile (from 0 to 100) is recorded as a separate counter, named `name.p{percentile}`,
+    /// such as `frame_time.p99`.
+    /// The percentiles are updated whenever a sample is recorded.
+    #[must_use]
+    pub fn with_histogram_percentiles(mut self, percentiles: &[u8], window: usize) -> Self {
+        self.histogram_percentiles = Some(HistogramPercentiles {
+            percentiles: percentiles.iter().map(|&p| p.min(100)).collect(),
+            window: window.max(1),
+        });
+        self
+    }
+
+    /// Set this as the global recorder.
+    ///
+    /// # Errors
+    ///
+    /// If a global recorder has already been set.
+    pub fn install(self) -> Result<(), SetRecorderError<Box<Self>>> {
+        metrics::set_global_recorder(Box::new(self))
+    }
+}
+
+impl Default for AndroidTraceRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Recorder for AndroidTraceRecorder {
+    fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+        Counter::from_arc(self.counters.get_or_create(key, || {
+            Arc::new(CounterState {
+                trace: self.trace.clone(),
+                name: counter_name(key, None),
+                total: AtomicU64::new(0),
+            })
+        }))
+    }
+
+    fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+        Gauge::from_arc(self.gauges.get_or_create(key, || {
+            Arc::new(GaugeState {
+                trace: self.trace.clone(),
+                name: counter_name(key, None),
+                value: AtomicU64::new(0_f64.to_bits()),
+            })
+        }))
+    }
+
+    fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+        Histogram::from_arc(self.histograms.get_or_create(key, || {
+            let mode = match &self.histogram_percentiles {
+                None => HistogramMode::Samples(counter_name(key, None)),
+                Some(config) => HistogramMode::Percentiles {
+                    names: config
+                        .percentiles
+                        .iter()
+                        .map(|&p| (p, counter_name(key, Some(p))))
+                        .collect(),
+                    window: config.window,
+                    samples: Mutex::new(VecDeque::with_capacity(config.window)),
+                },
+            };
+            Arc::new(HistogramState {
+                trace: self.trace.clone(),
+                mode,
+            })
+        }))
+    }
+}
+
+/// The state shared by every handle to the same metric.
+///
+/// The `metrics` macros register the metric on every use, so this is needed for counters to keep a
+/// single running total.
+#[derive(Debug)]
+struct Registry<T>(RwLock<HashMap<Key, T>>);
+
+impl<T> Default for Registry<T> {
+    fn default() -> Self {
+        Self(RwLock::default())
+    }
+}
+
+impl<T: Clone> Registry<T> {
+    fn get_or_create(&self, key: &Key, create: impl FnOnce() -> T) -> T {
+        if let Some(value) = self
+            .0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key)
+        {
+            return value.clone();
+        }
+        self.0
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(key.clone())
+            .or_insert_with(create)
+            .clone()
+    }
+}
+
+/// The name of the Android Trace counter for the metric `key`.
+///
+/// Null bytes are escaped, as they can't be included in the name.
+fn counter_name(key: &Key, percentile: Option<u8>) -> CString {
+    let mut name = key.name().to_owned();
+    let mut labels = key.labels().peekable();
+    if labels.peek().is_some() {
+        name.push('{');
+        for (i, label) in labels.enumerate() {
+            if i > 0 {
+                name.push(',');
+            }
+            // Writing to a String can't fail
+            let _ = write!(name, "{}={}", label.key(), label.value());
+        }
+        name.push('}');
+    }
+    if let Some(percentile) = percentile {
+        let _ = write!(name, ".p{percentile}");
+    }
+    CString::new(name.replace('\0', "\\0")).unwrap_or_default()
+}
+
+/// Convert a metric value to an Android Trace counter value.
+#[allow(
+    clippy::cast_possible_truncation,
+    // reason = "Casting a float to an integer saturates, which is the intended behaviour"
+)]
+fn to_counter_value(value: f64) -> i64 {
+    value.round() as i64
+}
+
+#[derive(Debug)]
+struct CounterState {
+    trace: AndroidTrace,
+    name: CString,
+    total: AtomicU64,
+}
+
+impl CounterState {
+    fn update(&self, total: u64) {
+        self.trace
+            .set_counter(&self.name, i64::try_from(total).unwrap_or(i64::MAX));
+    }
+}
+
+impl CounterFn for CounterState {
+    fn increment(&self, value: u64) {
+        let total = self
+            .total
+            .fetch_add(value, Ordering::Relaxed)
+            .wrapping_add(value);
+        self.update(total);
+    }
+
+    fn absolute(&self, value: u64) {
+        let total = self.total.fetch_max(value, Ordering::Relaxed).max(value);
+        self.update(total);
+    }
+}
+
+#[derive(Debug)]
+struct GaugeState {
+    trace: AndroidTrace,
+    name: CString,
+    /// The bits of the current [`f64`] value.
+    value: AtomicU64,
+}
+
+impl GaugeState {
+    fn update(&self, f: impl Fn(f64) -> f64) {
+        let mut new = 0.;
+        // The closure always returns `Some`, so this can't fail
+        let _ = self
+            .value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                new = f(f64::from_bits(bits));
+                Some(new.to_bits())
+            });
+        self.trace.set_counter(&self.name, to_counter_value(new));
+    }
+}
+
+impl GaugeFn for GaugeState {
+    fn increment(&self, value: f64) {
+        self.update(|current| current + value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update(|current| current - value);
+    }
+
+    fn set(&self, value: f64) {
+        self.update(|_| value);
+    }
+}
+
+#[derive(Debug)]
+struct HistogramState {
+    trace: AndroidTrace,
+    mode: HistogramMode,
+}
+
+#[derive(Debug)]
+enum HistogramMode {
+    Samples(CString),
+    Percentiles {
+        names: Vec<(u8, CString)>,
+        window: usize,
+        samples: Mutex<VecDeque<f64>>,
+    },
+}
+
+impl HistogramFn for HistogramState {
+    fn record(&self, value: f64) {
+        match &self.mode {
+            HistogramMode::Samples(name) => {
+                self.trace.set_counter(name, to_counter_value(value));
+            }
+            HistogramMode::Percentiles {
+                names,
+                window,
+                samples,
+            } => {
+                let mut sorted = {
+                    let mut samples = samples.lock().unwrap_or_else(PoisonError::into_inner);
+                    if samples.len() == *window {
+                        samples.pop_front();
+                    }
+                    samples.push_back(value);
+                    samples.iter().copied().collect::<Vec<_>>()
+                };
+                sorted.sort_unstable_by(f64::total_cmp);
+                for (percentile, name) in names {
+                    self.trace
+                        .set_counter(name, to_counter_value(percentile_of(&sorted, *percentile)));
+                }
+            }
+        }
+    }
+}
+
+/// The nearest-rank `percentile` of the non-empty slice `sorted`.
+fn percentile_of(sorted: &[f64], percentile: u8) -> f64 {
+    let rank = (sorted.len() * usize::from(percentile)).div_ceil(100);
+    sorted[rank.saturating_sub(1)]
+}
+
+#[cfg(test)]
+mod test {
+    use metrics::{Key, Label};
+
+    use super::{counter_name, percentile_of};
+
+    #[test]
+    fn labels_are_in_name() {
+        let key = Key::from_parts(
+            "queue_length",
+            vec![Label::new("queue", "decode"), Label::new("thread", "2")],
+        );
+        assert_eq!(
+            counter_name(&key, None).as_c_str(),
+            c"queue_length{queue=decode,thread=2}",
+            "Labels should be included in order"
+        );
+        assert_eq!(
+            counter_name(&Key::from_name("frame_time"), Some(99)).as_c_str(),
+            c"frame_time.p99",
+            "Percentiles should be a suffix"
+        );
+    }
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let sorted = [1., 2., 3., 4., 5., 6., 7., 8., 9., 10.];
+        assert_eq!(percentile_of(&sorted, 0), 1., "p0 is the minimum");
+        assert_eq!(percentile_of(&sorted, 50), 5., "p50 is the median");
+        assert_eq!(percentile_of(&sorted, 95), 10., "p95 rounds up");
+        assert_eq!(percentile_of(&sorted, 100), 10., "p100 is the maximum");
+    }
+}
@@ -0,0 +1,313 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Periodically recording the resource usage of the process using Android Trace.
+//!
+//! See [`ProcSampler`] for details.
+
+use std::{
+    ffi::CString,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::AndroidTrace;
+
+/// Samples the resource usage of the process from `/proc/self`, and records it as counters.
+///
+/// The following counters are recorded:
+/// - `proc.rss_bytes`: The resident set size, from `statm`.
+/// - `proc.swap_bytes`: The amount of swapped out memory (`VmSwap`), from `status`.
+/// - `proc.threads`: The number of threads, from `status`.
+/// - `proc.voluntary_ctxt_switches` and `proc.involuntary_ctxt_switches`: The number of context
+///   switches of the main thread, from `status`.
+/// - `proc.user_cpu_ms` and `proc.system_cpu_ms`: The CPU time used by the process, from `stat`.
+/// - `thread cpu ns: {name} ({tid})`: The CPU time used by each thread, from `task/{tid}/schedstat`.
+///   This can be disabled using [`with_thread_counters`](Self::with_thread_counters).
+///
+/// Files which can't be read or parsed (such as `schedstat` on kernels without `CONFIG_SCHEDSTATS`) are skipped.
+///
+/// ## Usage
+///
+/// ```no_run
+/// use android_trace::proc_sampler::ProcSampler;
+/// use std::time::Duration;
+///
+/// let sampler = ProcSampler::new()
+///     .with_interval(Duration::from_millis(50))
+///     .spawn()
+///     .unwrap();
+/// // Sampling stops when the handle is dropped, unless it is detached
+/// sampler.detach();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProcSampler {
+    trace: AndroidTrace,
+    proc_root: PathBuf,
+    interval: Duration,
+    thread_counters: bool,
+    page_size: u64,
+    clock_ticks_per_second: u64,
+}
+
+impl ProcSampler {
+    /// Create a `ProcSampler` which reads from `/proc/self` every 100ms.
+    pub fn new() -> Self {
+        // Safety: `sysconf` has no preconditions
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        // Safety: `sysconf` has no preconditions
+        let clock_ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        Self {
+            trace: AndroidTrace::new(),
+            proc_root: PathBuf::from("/proc/self"),
+            interval: Duration::from_millis(100),
+            thread_counters: true,
+            page_size: u64::try_from(page_size).unwrap_or(4096),
+            clock_ticks_per_second: u64::try_from(clock_ticks_per_second)
+                .ok()
+                .filter(|&ticks| ticks > 0)
+                .unwrap_or(100),
+        }
+    }
+
+    /// Use `trace` rather than a new [`AndroidTrace`].
+    #[must_use]
+    pub fn with_trace(mut self, trace: AndroidTrace) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Read from `proc_root` instead of `/proc/self`.
+    ///
+    /// This can be the directory of another process, such as `/proc/1234`, or a directory with the same
+    /// layout for testing.
+    #[must_use]
+    pub fn with_proc_root(mut self, proc_root: impl Into<PathBuf>) -> Self {
+        self.proc_root = proc_root.into();
+        self
+    }
+
+    /// Set the time between samples taken by [`spawn`](Self::spawn).
+    #[must_use]
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Whether to record the CPU time of each thread.
+    ///
+    /// This reads two files per thread on each sample, so may be worth disabling in processes with
+    /// many threads.
+    #[must_use]
+    pub fn with_thread_counters(mut self, thread_counters: bool) -> Self {
+        self.thread_counters = thread_counters;
+        self
+    }
+
+    /// Take a single sample, if tracing is enabled.
+    pub fn sample(&self) {
+        if !self.trace.is_enabled().unwrap_or(false) {
+            return;
+        }
+        for (name, value) in self.counters() {
+            let Ok(name) = CString::new(name) else {
+                continue;
+            };
+            self.trace.set_counter(&name, value);
+        }
+    }
+
+    /// Take a sample every interval on a new thread, until the returned handle is dropped.
+    ///
+    /// # Errors
+    ///
+    /// If the thread could not be spawned.
+    pub fn spawn(self) -> io::Result<ProcSamplerHandle> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("proc sampler".into())
+            .spawn(move || loop {
+                self.sample();
+                if let Err(RecvTimeoutError::Disconnected) = stopped.recv_timeout(self.interval) {
+                    break;
+                }
+            })?;
+        Ok(ProcSamplerHandle {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Read the value of each counter.
+    fn counters(&self) -> Vec<(String, i64)> {
+        let mut counters = Vec::new();
+        let mut push = |name: &str, value: u64| {
+            counters.push((name.to_owned(), i64::try_from(value).unwrap_or(i64::MAX)));
+        };
+        if let Some(resident_pages) = self
+            .read("statm")
+            .and_then(|statm| statm.split_whitespace().nth(1)?.parse::<u64>().ok())
+        {
+            push(
+                "proc.rss_bytes",
+                resident_pages.saturating_mul(self.page_size),
+            );
+        }
+        if let Some(status) = self.read("status") {
+            for line in status.lines() {
+                let Some((key, value)) = line.split_once(':') else {
+                    continue;
+                };
+                let (name, scale) = match key {
+                    "VmSwap" => ("proc.swap_bytes", 1024),
+                    "Threads" => ("proc.threads", 1),
+                    "voluntary_ctxt_switches" => ("proc.voluntary_ctxt_switches", 1),
+                    "nonvoluntary_ctxt_switches" => ("proc.involuntary_ctxt_switches", 1),
+                    _ => continue,
+                };
+                // `VmSwap` is given in kB
+                let value = value.trim().trim_end_matches("kB").trim_end();
+                if let Ok(value) = value.parse::<u64>() {
+                    push(name, value.saturating_mul(scale));
+                }
+            }
+        }
+        if let Some(stat) = self.read("stat") {
+            // The name of the process is in parentheses, and can contain spaces and parentheses itself
+            let fields = stat
+                .rsplit_once(')')
+                .map(|(_, rest)| rest.split_whitespace().collect::<Vec<_>>())
+                .unwrap_or_default();
+            // `utime` and `stime` are the 14th and 15th fields, and `fields` starts at the 3rd
+            let ticks_to_ms = |ticks: u64| ticks.saturating_mul(1000) / self.clock_ticks_per_second;
+            if let Some(utime) = fields.get(11).and_then(|field| field.parse().ok()) {
+                push("proc.user_cpu_ms", ticks_to_ms(utime));
+            }
+            if let Some(stime) = fields.get(12).and_then(|field| field.parse().ok()) {
+                push("proc.system_cpu_ms", ticks_to_ms(stime));
+            }
+        }
+        if self.thread_counters {
+            self.thread_cpu_counters(&mut counters);
+        }
+        counters
+    }
+
+    /// Read the CPU time of each thread.
+    fn thread_cpu_counters(&self, counters: &mut Vec<(String, i64)>) {
+        let Ok(tasks) = fs::read_dir(self.proc_root.join("task")) else {
+            return;
+        };
+        for task in tasks.flatten() {
+            let tid = task.file_name();
+            let tid = tid.to_string_lossy();
+            let task = task.path();
+            let Some(cpu_ns) = read(&task.join("schedstat"))
+                .and_then(|schedstat| schedstat.split_whitespace().next()?.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            let name = read(&task.join("comm")).unwrap_or_default();
+            counters.push((
+                format!("thread cpu ns: {} ({tid})", name.trim_end()),
+                i64::try_from(cpu_ns).unwrap_or(i64::MAX),
+            ));
+        }
+    }
+
+    fn read(&self, file: &str) -> Option<String> {
+        read(&self.proc_root.join(file))
+    }
+}
+
+impl Default for ProcSampler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn read(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok()
+}
+
+/// Stops the sampling thread started by [`ProcSampler::spawn`] when dropped.
+#[derive(Debug)]
+#[must_use = "Sampling stops when this handle is dropped"]
+pub struct ProcSamplerHandle {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ProcSamplerHandle {
+    /// Keep sampling until the process exits.
+    pub fn detach(mut self) {
+        // The thread stops once every sender has been dropped, so leak it
+        std::mem::forget(self.stop.take());
+        self.thread = None;
+    }
+}
+
+impl Drop for ProcSamplerHandle {
+    fn drop(&mut self) {
+        // The thread stops once the sender has been dropped
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::ProcSampler;
+
+    #[test]
+    fn reads_fake_proc() {
+        let root = std::env::temp_dir().join(format!("android_trace_proc_{}", std::process::id()));
+        let task = root.join("task");
+        fs::create_dir_all(task.join("101")).unwrap();
+        fs::create_dir_all(task.join("102")).unwrap();
+        fs::write(root.join("statm"), "5000 300 100 20 0 400 0\n").unwrap();
+        fs::write(
+            root.join("status"),
+            "Name:\tapp\nVmRSS:\t    1200 kB\nVmSwap:\t      16 kB\nThreads:\t2\n\
+             voluntary_ctxt_switches:\t40\nnonvoluntary_ctxt_switches:\t7\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("stat"),
+            "101 (my (app)) S 1 101 0 0 -1 4194560 100 0 0 0 250 50 0 0 20 0 2 0 100\n",
+        )
+        .unwrap();
+        fs::write(task.join("101/comm"), "my app\n").unwrap();
+        fs::write(task.join("101/schedstat"), "1500000 20000 30\n").unwrap();
+        // Threads without schedstat are skipped
+        fs::write(task.join("102/comm"), "worker\n").unwrap();
+        let mut sampler = ProcSampler::new().with_proc_root(&root);
+        sampler.page_size = 4096;
+        sampler.clock_ticks_per_second = 100;
+        let counters = sampler.counters();
+        fs::remove_dir_all(&root).unwrap();
+
+        let expected = [
+            ("proc.rss_bytes", 300 * 4096),
+            ("proc.swap_bytes", 16 * 1024),
+            ("proc.threads", 2),
+            ("proc.voluntary_ctxt_switches", 40),
+            ("proc.involuntary_ctxt_switches", 7),
+            ("proc.user_cpu_ms", 2500),
+            ("proc.system_cpu_ms", 500),
+            ("thread cpu ns: my app (101)", 1_500_000),
+        ];
+        let expected = expected
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value))
+            .collect::<Vec<_>>();
+        assert_eq!(counters, expected);
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Scope macros with the same interface as the [`profiling`](https://docs.rs/profiling) crate,
+//! which record directly to Android Trace.
+//!
+//! The `profiling` crate chooses its backend using its own feature flags, so it can't be extended
+//! with an Android Trace backend.
+//! Instead, code which uses its macros can use the macros from this crate on Android:
+//!
+//! ```no_run
+//! #[cfg(target_os = "android")]
+//! use android_trace::{function_scope, scope};
+//! #[cfg(not(target_os = "android"))]
+//! use profiling::{function_scope, scope};
+//!
+//! fn encode(frame: u32) {
+//!     function_scope!();
+//!     for pass in 0..3 {
+//!         scope!("pass", &format!("frame {frame}, pass {pass}"));
+//!     }
+//! }
+//! ```
+//!
+//! Scopes are recorded as sync sections using [`AndroidTrace::begin_section`], which end when the
+//! scope does.
+//! When the name of a scope is a string literal and no data is given, the section name is created
+//! at compile time, so entering the scope doesn't allocate.
+//! Otherwise, the data is appended to the name, separated by a space.
+//!
+//! The `#[profiling::function]` attribute is not supported, as it would require a procedural macro.
+//! [`function_scope!`](crate::function_scope) can be used instead.
+
+use std::{
+    ffi::{CStr, CString},
+    sync::OnceLock,
+};
+
+use crate::AndroidTrace;
+
+/// The [`AndroidTrace`] used by the scope macros.
+fn trace() -> &'static AndroidTrace {
+    static TRACE: OnceLock<AndroidTrace> = OnceLock::new();
+    TRACE.get_or_init(AndroidTrace::new)
+}
+
+/// Whether the scope macros are currently recording sections.
+pub(crate) fn is_enabled() -> bool {
+    trace().is_enabled().unwrap_or(false)
+}
+
+/// Ends the section started by a scope macro when dropped.
+///
+/// This is public as it is used by the macros, but is not intended for direct use.
+#[doc(hidden)]
+#[derive(Debug)]
+#[must_use = "The section ends when the guard is dropped"]
+pub struct ScopeGuard {
+    began: bool,
+}
+
+impl ScopeGuard {
+    /// Begin a section named `name`, if tracing is enabled.
+    ///
+    /// The section is only ended if it was begun, so tracing being enabled whilst the scope is
+    /// active doesn't end an unrelated section.
+    pub fn new(name: &CStr) -> Self {
+        let trace = trace();
+        if !trace.is_enabled().unwrap_or(false) {
+            return Self { began: false };
+        }
+        trace.begin_section(name);
+        Self { began: true }
+    }
+
+    /// Begin a section named `name`, followed by `data` if present.
+    ///
+    /// The name is only created if tracing is enabled.
+    pub fn new_with_data(name: &str, data: Option<&str>) -> Self {
+        if !is_enabled() {
+            return Self { began: false };
+        }
+        let name = match data {
+            Some(data) => format!("{name} {data}"),
+            None => name.to_owned(),
+        };
+        match CString::new(name.replace('\0', "\\0")) {
+            Ok(name) => Self::new(&name),
+            Err(_) => Self { began: false },
+        }
+    }
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        if self.began {
+            trace().end_section();
+        }
+    }
+}
+
+/// The name of the function containing the type `S`, from its [type name](std::any::type_name).
+///
+/// This is public as it is used by the macros, but is not intended for direct use.
+#[doc(hidden)]
+pub fn function_name(type_name: &'static str) -> &'static str {
+    type_name.strip_suffix("::S").unwrap_or(type_name)
+}
+
+/// Record a sync section until the end of the enclosing scope.
+///
+/// Has the same interface as `profiling::scope!`:
+/// - `scope!(name)` records a section named `name`.
+/// - `scope!(name, data)` records a section named `name`, followed by the string `data`.
+///
+/// See the [module level documentation](crate::profiling) for details.
+#[macro_export]
+macro_rules! scope {
+    ($name:literal) => {
+        let _android_trace_guard = {
+            const NAME: &::core::ffi::CStr =
+                match ::core::ffi::CStr::from_bytes_with_nul(concat!($name, "\0").as_bytes()) {
+                    Ok(name) => name,
+                    Err(_) => panic!("Scope names must not contain a null byte"),
+                };
+            $crate::profiling::ScopeGuard::new(NAME)
+        };
+    };
+    ($name:expr) => {
+        let _android_trace_guard = $crate::profiling::ScopeGuard::new_with_data($name, None);
+    };
+    ($name:expr, $data:expr) => {
+        let _android_trace_guard = $crate::profiling::ScopeGuard::new_with_data($name, Some($data));
+    };
+}
+
+/// Record a sync section named after the current function, until the end of the enclosing scope.
+///
+/// Has the same interface as `profiling::function_scope!`:
+/// - `function_scope!()` records a section named after the function.
+/// - `function_scope!(data)` records a section named after the function, followed by the string `data`.
+///
+/// See the [module level documentation](crate::profiling) for details.
+#[macro_export]
+macro_rules! function_scope {
+    () => {
+        let _android_trace_guard = {
+            struct S;
+            static NAME: ::std::sync::OnceLock<::std::ffi::CString> = ::std::sync::OnceLock::new();
+            let name = NAME.get_or_init(|| {
+                let name = $crate::profiling::function_name(::core::any::type_name::<S>());
+                ::std::ffi::CString::new(name).unwrap_or_default()
+            });
+            $crate::profiling::ScopeGuard::new(name)
+        };
+    };
+    ($data:expr) => {
+        let _android_trace_guard = {
+            struct S;
+            $crate::profiling::ScopeGuard::new_with_data(
+                $crate::profiling::function_name(::core::any::type_name::<S>()),
+                Some($data),
+            )
+        };
+    };
+}
+
+/// Has the same interface as `profiling::register_thread!`, but does nothing.
+///
+/// Android Trace uses the name of the thread set by the operating system.
+#[macro_export]
+macro_rules! register_thread {
+    () => {};
+    ($name:expr) => {};
+}
+
+/// Has the same interface as `profiling::finish_frame!`, but does nothing.
+///
+/// Android Trace has no concept of frames.
+#[macro_export]
+macro_rules! finish_frame {
+    () => {};
+}
+
+#[cfg(test)]
+mod test {
+    use super::function_name;
+
+    #[test]
+    fn function_names() {
+        struct S;
+        assert_eq!(
+            function_name(core::any::type_name::<S>()),
+            "android_trace::profiling::test::function_names",
+            "The type name suffix should be removed"
+        );
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Scope macros which record to both [`puffin`] and Android Trace.
+//!
+//! `puffin` only reports the scopes of a thread once its outermost scope ends, so its scopes can't be
+//! forwarded to Android Trace as they happen.
+//! Instead, [`profile_scope!`](crate::profile_scope) and [`profile_function!`](crate::profile_function)
+//! have the same interface as the macros from `puffin`, and record each scope using both `puffin` and
+//! [`AndroidTrace::begin_section`](crate::AndroidTrace::begin_section).
+//! This means that the same scopes can be viewed in `puffin_viewer` and in Perfetto or Android GPU Inspector.
+//!
+//! The data of a scope is appended to its section name, separated by a space.
+//!
+//! ## Usage
+//!
+//! ```no_run
+//! #[cfg(target_os = "android")]
+//! use android_trace::{profile_function, profile_scope};
+//! #[cfg(not(target_os = "android"))]
+//! use puffin::{profile_function, profile_scope};
+//!
+//! fn layout(widgets: &[&str]) {
+//!     profile_function!();
+//!     for widget in widgets {
+//!         profile_scope!("widget", *widget);
+//!     }
+//! }
+//! ```
+
+#[doc(hidden)]
+pub use puffin;
+
+use crate::profiling::{self, ScopeGuard};
+
+/// Evaluate the data of a scope, if either `puffin` or Android Trace will record it.
+///
+/// This ensures the data expression is only evaluated once, even though it is used by both.
+///
+/// This is public as it is used by the macros, but is not intended for direct use.
+#[doc(hidden)]
+pub fn scope_data<T: AsRef<str>>(data: impl FnOnce() -> T) -> Option<T> {
+    (puffin::are_scopes_on() || profiling::is_enabled()).then(data)
+}
+
+/// The data from [`scope_data`], or an empty string if it wasn't evaluated.
+///
+/// This is public as it is used by the macros, but is not intended for direct use.
+#[doc(hidden)]
+pub fn data_str<T: AsRef<str>>(data: &Option<T>) -> &str {
+    data.as_ref().map_or("", AsRef::as_ref)
+}
+
+/// Begin a section for a scope with data from [`scope_data`], if tracing is enabled.
+///
+/// This is public as it is used by the macros, but is not intended for direct use.
+#[doc(hidden)]
+pub fn scope_with_data<T: AsRef<str>>(name: &str, data: &Option<T>) -> Option<ScopeGuard> {
+    if !profiling::is_enabled() {
+        return None;
+    }
+    let data = data_str(data);
+    Some(ScopeGuard::new_with_data(
+        name,
+        (!data.is_empty()).then_some(data),
+    ))
+}
+
+/// Profile the current scope with the given name, using both `puffin` and Android Trace.
+///
+/// Has the same interface as `puffin::profile_scope!`:
+/// - `profile_scope!(name)` records a scope named `name`.
+/// - `profile_scope!(name, data)` records a scope named `name`, with the string `data`.
+///
+/// See the [module level documentation](crate::puffin_bridge) for details.
+#[macro_export]
+macro_rules! profile_scope {
+    ($name:literal) => {
+        $crate::puffin_bridge::puffin::profile_scope!($name);
+        $crate::scope!($name);
+    };
+    ($name:expr) => {
+        $crate::profile_scope!($name, "");
+    };
+    ($name:expr, $data:expr) => {
+        let _android_trace_data = $crate::puffin_bridge::scope_data(|| $data);
+        $crate::puffin_bridge::puffin::profile_scope!(
+            $name,
+            $crate::puffin_bridge::data_str(&_android_trace_data)
+        );
+        let _android_trace_guard =
+            $crate::puffin_bridge::scope_with_data($name, &_android_trace_data);
+    };
+}
+
+/// Profile the current function, using both `puffin` and Android Trace.
+///
+/// Has the same interface as `puffin::profile_function!`:
+/// - `profile_function!()` records a scope named after the function.
+/// - `profile_function!(data)` records a scope named after the function, with the string `data`.
+///
+/// See the [module level documentation](crate::puffin_bridge) for details.
+#[macro_export]
+macro_rules! profile_function {
+    () => {
+        $crate::puffin_bridge::puffin::profile_function!();
+        $crate::function_scope!();
+    };
+    ($data:expr) => {
+        let _android_trace_data = $crate::puffin_bridge::scope_data(|| $data);
+        $crate::puffin_bridge::puffin::profile_function!($crate::puffin_bridge::data_str(
+            &_android_trace_data
+        ));
+        let _android_trace_guard = {
+            struct S;
+            $crate::puffin_bridge::scope_with_data(
+                $crate::profiling::function_name(::core::any::type_name::<S>()),
+                &_android_trace_data,
+            )
+        };
+    };
+}
@@ -0,0 +1,84 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! An in-process stand-in for the Android tracing functions, for testing code which uses this crate.
+//!
+//! When the `recording` feature is enabled, the methods of [`AndroidTrace`](crate::AndroidTrace) record
+//! what they would have written to the trace as [`Event`]s, rather than calling into Android.
+//! Tracing is disabled on each thread until [`set_enabled`] is called on that thread, and only the calls
+//! made whilst it is enabled are recorded, matching the behaviour of Android.
+//! The events are recorded separately for each thread, so tests can run in parallel.
+//!
+//! This feature also allows this crate to be built for platforms other than Android, so that tests
+//! can run on the host. It should never be enabled outside of tests.
+//!
+//! ```no_run
+//! use android_trace::{recording::{self, Event}, AndroidTrace};
+//!
+//! recording::set_enabled(true);
+//! let trace = AndroidTrace::new();
+//! trace.begin_section(c"Work");
+//! trace.end_section();
+//! assert_eq!(
+//!     recording::take_events(),
+//!     [Event::Begin("Work".into()), Event::End],
+//! );
+//! ```
+
+use std::{
+    cell::{Cell, RefCell},
+    ffi::CStr,
+};
+
+/// A call to one of the Android tracing functions, recorded whilst tracing was enabled.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Event {
+    /// `ATrace_beginSection`, with the section name.
+    Begin(String),
+    /// `ATrace_endSection`.
+    End,
+    /// `ATrace_beginAsyncSection`, with the section name and cookie.
+    BeginAsync(String, i32),
+    /// `ATrace_endAsyncSection`, with the section name and cookie.
+    EndAsync(String, i32),
+    /// `ATrace_setCounter`, with the counter name and value.
+    Counter(String, i64),
+}
+
+thread_local! {
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
+    static EVENTS: RefCell<Vec<Event>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Set whether tracing is enabled on the current thread.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.with(|cell| cell.set(enabled));
+}
+
+/// Remove and return the events recorded on the current thread.
+pub fn take_events() -> Vec<Event> {
+    EVENTS.with(|events| std::mem::take(&mut *events.borrow_mut()))
+}
+
+pub(crate) fn is_enabled() -> bool {
+    ENABLED.try_with(Cell::get).unwrap_or(false)
+}
+
+pub(crate) fn record(event: impl FnOnce() -> Event) {
+    if !is_enabled() {
+        return;
+    }
+    // Creating the event can allocate, so it is created before borrowing the events, in case
+    // this is re-entered from inside a `TracingAllocator`
+    let event = event();
+    let _ = EVENTS.try_with(|events| {
+        if let Ok(mut events) = events.try_borrow_mut() {
+            events.push(event);
+        }
+    });
+}
+
+pub(crate) fn name(name: &CStr) -> String {
+    name.to_string_lossy().into_owned()
+}
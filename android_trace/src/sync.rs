@@ -0,0 +1,442 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Locks which record contention using Android Trace.
+//!
+//! See [`TracedMutex`] and [`TracedRwLock`] for details.
+
+use std::{
+    ffi::CString,
+    fmt::{self, Debug},
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        LockResult, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        TryLockError, TryLockResult,
+    },
+};
+
+use crate::AndroidTrace;
+
+/// The sections and counters recorded for a lock.
+struct LockTracing {
+    trace: AndroidTrace,
+    wait_name: CString,
+    hold_name: CString,
+    waiters_name: CString,
+    hold_sections: bool,
+    waiter_counter: bool,
+    waiters: AtomicI64,
+}
+
+impl LockTracing {
+    fn new(name: &str) -> Self {
+        let name = name.replace('\0', "\\0");
+        let c_string = |prefix: &str| CString::new(format!("{prefix}: {name}")).unwrap_or_default();
+        Self {
+            trace: AndroidTrace::new(),
+            wait_name: c_string("lock wait"),
+            hold_name: c_string("lock held"),
+            waiters_name: c_string("lock waiters"),
+            hold_sections: false,
+            waiter_counter: false,
+            waiters: AtomicI64::new(0),
+        }
+    }
+
+    /// Acquire a contended lock using `acquire`, recording the wait if tracing is enabled.
+    fn wait<G>(&self, acquire: impl FnOnce() -> G) -> G {
+        if !self.trace.is_enabled().unwrap_or(false) {
+            return acquire();
+        }
+        if self.waiter_counter {
+            let waiters = self.waiters.fetch_add(1, Ordering::Relaxed) + 1;
+            self.trace.set_counter(&self.waiters_name, waiters);
+        }
+        self.trace.begin_section(&self.wait_name);
+        let guard = acquire();
+        self.trace.end_section();
+        if self.waiter_counter {
+            let waiters = self.waiters.fetch_sub(1, Ordering::Relaxed) - 1;
+            self.trace.set_counter(&self.waiters_name, waiters);
+        }
+        guard
+    }
+
+    /// Begin the hold section, if enabled. Returns whether the section began.
+    fn begin_hold(&self) -> bool {
+        if self.hold_sections && self.trace.is_enabled().unwrap_or(false) {
+            self.trace.begin_section(&self.hold_name);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn end_hold(&self, held: bool) {
+        if held {
+            self.trace.end_section();
+        }
+    }
+}
+
+impl Debug for LockTracing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LockTracing")
+            .field("wait_name", &self.wait_name)
+            .field("hold_sections", &self.hold_sections)
+            .field("waiter_counter", &self.waiter_counter)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Map the guard inside a lock result, keeping whether the lock was poisoned.
+fn map_result<G, H>(result: LockResult<G>, f: impl FnOnce(G) -> H) -> LockResult<H> {
+    match result {
+        Ok(guard) => Ok(f(guard)),
+        Err(poisoned) => Err(PoisonError::new(f(poisoned.into_inner()))),
+    }
+}
+
+/// A [`Mutex`] which records a `lock wait: {name}` section whilst a thread is blocked waiting for it.
+///
+/// The lock is first acquired without blocking, and the section is only recorded if that fails,
+/// so there is negligible overhead if the lock isn't contended or tracing is not enabled.
+///
+/// Optionally, a `lock held: {name}` section can be recorded whilst the lock is held
+/// (using [`with_hold_sections`](Self::with_hold_sections)), and the number of threads waiting for the lock can
+/// be recorded as the `lock waiters: {name}` counter (using [`with_waiter_counter`](Self::with_waiter_counter)).
+///
+/// ## Usage
+///
+/// ```no_run
+/// use android_trace::sync::TracedMutex;
+///
+/// let frames = TracedMutex::new("frame queue", Vec::<u32>::new());
+/// frames.lock().unwrap().push(1);
+/// ```
+#[derive(Debug)]
+pub struct TracedMutex<T> {
+    inner: Mutex<T>,
+    tracing: LockTracing,
+}
+
+impl<T> TracedMutex<T> {
+    /// Create a `TracedMutex` containing `value`, whose sections are named after `name`.
+    pub fn new(name: &str, value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+            tracing: LockTracing::new(name),
+        }
+    }
+
+    /// Whether to record a section whilst the lock is held.
+    ///
+    /// As sections on a thread must be nested, guards should be dropped in the reverse order
+    /// to which they were acquired if this is enabled.
+    #[must_use]
+    pub fn with_hold_sections(mut self, hold_sections: bool) -> Self {
+        self.tracing.hold_sections = hold_sections;
+        self
+    }
+
+    /// Whether to record the number of threads waiting for the lock as a counter.
+    #[must_use]
+    pub fn with_waiter_counter(mut self, waiter_counter: bool) -> Self {
+        self.tracing.waiter_counter = waiter_counter;
+        self
+    }
+
+    /// Acquire the lock, blocking the current thread until it is available.
+    ///
+    /// See [`Mutex::lock`].
+    ///
+    /// # Errors
+    ///
+    /// If the mutex is poisoned.
+    pub fn lock(&self) -> LockResult<TracedMutexGuard<'_, T>> {
+        let result = match self.inner.try_lock() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::Poisoned(poisoned)) => Err(poisoned),
+            Err(TryLockError::WouldBlock) => self.tracing.wait(|| self.inner.lock()),
+        };
+        map_result(result, |guard| self.guard(guard))
+    }
+
+    /// Attempt to acquire the lock without blocking.
+    ///
+    /// See [`Mutex::try_lock`].
+    ///
+    /// # Errors
+    ///
+    /// If the lock could not be acquired, or the mutex is poisoned.
+    pub fn try_lock(&self) -> TryLockResult<TracedMutexGuard<'_, T>> {
+        match self.inner.try_lock() {
+            Ok(guard) => Ok(self.guard(guard)),
+            Err(TryLockError::Poisoned(poisoned)) => Err(TryLockError::Poisoned(PoisonError::new(
+                self.guard(poisoned.into_inner()),
+            ))),
+            Err(TryLockError::WouldBlock) => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Get a mutable reference to the value, which doesn't need to acquire the lock.
+    ///
+    /// # Errors
+    ///
+    /// If the mutex is poisoned.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+
+    /// Consume this mutex, returning the value.
+    ///
+    /// # Errors
+    ///
+    /// If the mutex is poisoned.
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+
+    fn guard<'a>(&'a self, guard: MutexGuard<'a, T>) -> TracedMutexGuard<'a, T> {
+        TracedMutexGuard {
+            held: self.tracing.begin_hold(),
+            guard,
+            tracing: &self.tracing,
+        }
+    }
+}
+
+/// The guard of a [`TracedMutex`], which releases the lock when dropped.
+#[must_use = "if unused the Mutex will immediately unlock"]
+pub struct TracedMutexGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    tracing: &'a LockTracing,
+    held: bool,
+}
+
+impl<T> Deref for TracedMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for TracedMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for TracedMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.tracing.end_hold(self.held);
+    }
+}
+
+impl<T: Debug> Debug for TracedMutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&*self.guard, f)
+    }
+}
+
+/// A [`RwLock`] which records a `lock wait: {name}` section whilst a thread is blocked waiting for it.
+///
+/// This behaves in the same way as [`TracedMutex`], for both readers and writers.
+///
+/// ## Usage
+///
+/// ```no_run
+/// use android_trace::sync::TracedRwLock;
+///
+/// let settings = TracedRwLock::new("settings", String::from("dark"));
+/// let theme = settings.read().unwrap().clone();
+/// ```
+#[derive(Debug)]
+pub struct TracedRwLock<T> {
+    inner: RwLock<T>,
+    tracing: LockTracing,
+}
+
+impl<T> TracedRwLock<T> {
+    /// Create a `TracedRwLock` containing `value`, whose sections are named after `name`.
+    pub fn new(name: &str, value: T) -> Self {
+        Self {
+            inner: RwLock::new(value),
+            tracing: LockTracing::new(name),
+        }
+    }
+
+    /// Whether to record a section whilst the lock is held.
+    ///
+    /// As sections on a thread must be nested, guards should be dropped in the reverse order
+    /// to which they were acquired if this is enabled.
+    #[must_use]
+    pub fn with_hold_sections(mut self, hold_sections: bool) -> Self {
+        self.tracing.hold_sections = hold_sections;
+        self
+    }
+
+    /// Whether to record the number of threads waiting for the lock as a counter.
+    #[must_use]
+    pub fn with_waiter_counter(mut self, waiter_counter: bool) -> Self {
+        self.tracing.waiter_counter = waiter_counter;
+        self
+    }
+
+    /// Acquire shared read access, blocking the current thread until it is available.
+    ///
+    /// See [`RwLock::read`].
+    ///
+    /// # Errors
+    ///
+    /// If the lock is poisoned.
+    pub fn read(&self) -> LockResult<TracedRwLockReadGuard<'_, T>> {
+        let result = match self.inner.try_read() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::Poisoned(poisoned)) => Err(poisoned),
+            Err(TryLockError::WouldBlock) => self.tracing.wait(|| self.inner.read()),
+        };
+        map_result(result, |guard| TracedRwLockReadGuard {
+            held: self.tracing.begin_hold(),
+            guard,
+            tracing: &self.tracing,
+        })
+    }
+
+    /// Acquire exclusive write access, blocking the current thread until it is available.
+    ///
+    /// See [`RwLock::write`].
+    ///
+    /// # Errors
+    ///
+    /// If the lock is poisoned.
+    pub fn write(&self) -> LockResult<TracedRwLockWriteGuard<'_, T>> {
+        let result = match self.inner.try_write() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::Poisoned(poisoned)) => Err(poisoned),
+            Err(TryLockError::WouldBlock) => self.tracing.wait(|| self.inner.write()),
+        };
+        map_result(result, |guard| TracedRwLockWriteGuard {
+            held: self.tracing.begin_hold(),
+            guard,
+            tracing: &self.tracing,
+        })
+    }
+
+    /// Get a mutable reference to the value, which doesn't need to acquire the lock.
+    ///
+    /// # Errors
+    ///
+    /// If the lock is poisoned.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+
+    /// Consume this lock, returning the value.
+    ///
+    /// # Errors
+    ///
+    /// If the lock is poisoned.
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+/// The read guard of a [`TracedRwLock`], which releases shared access when dropped.
+#[must_use = "if unused the RwLock will immediately unlock"]
+pub struct TracedRwLockReadGuard<'a, T> {
+    guard: RwLockReadGuard<'a, T>,
+    tracing: &'a LockTracing,
+    held: bool,
+}
+
+impl<T> Deref for TracedRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> Drop for TracedRwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.tracing.end_hold(self.held);
+    }
+}
+
+impl<T: Debug> Debug for TracedRwLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&*self.guard, f)
+    }
+}
+
+/// The write guard of a [`TracedRwLock`], which releases exclusive access when dropped.
+#[must_use = "if unused the RwLock will immediately unlock"]
+pub struct TracedRwLockWriteGuard<'a, T> {
+    guard: RwLockWriteGuard<'a, T>,
+    tracing: &'a LockTracing,
+    held: bool,
+}
+
+impl<T> Deref for TracedRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for TracedRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for TracedRwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.tracing.end_hold(self.held);
+    }
+}
+
+impl<T: Debug> Debug for TracedRwLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&*self.guard, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, thread};
+
+    use super::TracedMutex;
+
+    #[test]
+    fn contended_lock_and_poison() {
+        let mutex = Arc::new(TracedMutex::new("test", 0).with_waiter_counter(true));
+        let threads = (0..4)
+            .map(|_| {
+                let mutex = Arc::clone(&mutex);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        *mutex.lock().unwrap() += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*mutex.lock().unwrap(), 400, "Every increment should apply");
+
+        let poisoner = Arc::clone(&mutex);
+        let poisoned = thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("Poison the mutex");
+        })
+        .join();
+        assert!(poisoned.is_err(), "The thread should have panicked");
+        assert!(mutex.lock().is_err(), "Poisoning should be reported");
+    }
+}
@@ -0,0 +1,300 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Support for recording the activity of a [`tokio`] runtime using Android Trace.
+//!
+//! See [`RuntimeTracing`] for details.
+
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+};
+
+use tokio::runtime::{Builder, Handle};
+
+use crate::AndroidTrace;
+
+#[cfg(tokio_unstable)]
+use {
+    crate::cookie::CookieAllocator,
+    std::{
+        cell::RefCell,
+        collections::HashMap,
+        ffi::{CStr, CString},
+        panic::Location,
+        rc::Rc,
+        sync::{Mutex, PoisonError},
+    },
+    tokio::{runtime::TaskMeta, task::Id},
+};
+
+/// Records the activity of a `tokio` runtime using Android Trace, by installing hooks on its
+/// [`Builder`].
+///
+/// The following are recorded:
+/// - A `tokio worker busy` sync section on each worker thread whilst it is not parked.
+/// - The counters `tokio.busy_workers`, `tokio.alive_tasks` and `tokio.global_queue_depth`,
+///   which are updated whenever a worker thread starts, stops, parks or unparks.
+/// - If built with `--cfg tokio_unstable`, an async section from when each task is spawned until it
+///   terminates, named after the location the task was spawned at (such as `task src/main.rs:12`).
+///   This requires Android API level 29.
+/// - If built with `--cfg tokio_unstable` and [`with_poll_sections`](Self::with_poll_sections) is
+///   used, a sync section around each poll of a task, with the same name.
+///
+/// ## Usage
+///
+/// ```no_run
+/// use android_trace::tokio_runtime::RuntimeTracing;
+///
+/// let mut builder = tokio::runtime::Builder::new_multi_thread();
+/// RuntimeTracing::new().install(&mut builder);
+/// let runtime = builder.enable_all().build().unwrap();
+/// ```
+///
+/// A thread is busy from when it starts until it first parks.
+/// As `tokio` only reports when worker threads park, the threads of the blocking pool are
+/// counted as busy for as long as they are alive.
+///
+/// Note that installing these hooks replaces any thread start, stop, park and unpark hooks
+/// (and task spawn, terminate and poll hooks) which were previously set on the builder.
+#[derive(Debug, Clone)]
+pub struct RuntimeTracing {
+    trace: AndroidTrace,
+    busy_sections: bool,
+    counters: bool,
+    poll_sections: bool,
+}
+
+impl RuntimeTracing {
+    /// Create a `RuntimeTracing`, which records busy sections and counters.
+    pub fn new() -> Self {
+        Self::with_trace(AndroidTrace::new())
+    }
+
+    /// Create a `RuntimeTracing` from a pre-existing [`AndroidTrace`].
+    pub fn with_trace(trace: AndroidTrace) -> Self {
+        Self {
+            trace,
+            busy_sections: true,
+            counters: true,
+            poll_sections: false,
+        }
+    }
+
+    /// Whether to record a section on each worker thread whilst it is not parked.
+    #[must_use]
+    pub fn with_busy_sections(mut self, busy_sections: bool) -> Self {
+        self.busy_sections = busy_sections;
+        self
+    }
+
+    /// Whether to record the number of busy workers, alive tasks and queued tasks as counters.
+    #[must_use]
+    pub fn with_counters(mut self, counters: bool) -> Self {
+        self.counters = counters;
+        self
+    }
+
+    /// Whether to record a section around each poll of a task.
+    ///
+    /// This has no effect unless built with `--cfg tokio_unstable`.
+    #[must_use]
+    pub fn with_poll_sections(mut self, poll_sections: bool) -> Self {
+        self.poll_sections = poll_sections;
+        self
+    }
+
+    /// Install the hooks on `builder`.
+    pub fn install(self, builder: &mut Builder) -> &mut Builder {
+        let state = Arc::new(State {
+            config: self,
+            busy_workers: AtomicI64::new(0),
+            #[cfg(tokio_unstable)]
+            tasks: Mutex::default(),
+        });
+        {
+            let state = Arc::clone(&state);
+            builder.on_thread_start(move || state.unpark());
+        }
+        {
+            let state = Arc::clone(&state);
+            builder.on_thread_unpark(move || state.unpark());
+        }
+        {
+            let state = Arc::clone(&state);
+            builder.on_thread_park(move || state.park());
+        }
+        {
+            let state = Arc::clone(&state);
+            builder.on_thread_stop(move || state.park());
+        }
+        #[cfg(tokio_unstable)]
+        {
+            {
+                let state = Arc::clone(&state);
+                builder.on_task_spawn(move |meta| state.task_spawned(meta));
+            }
+            {
+                let state = Arc::clone(&state);
+                builder.on_task_terminate(move |meta| state.task_terminated(meta));
+            }
+            if state.config.poll_sections {
+                {
+                    let state = Arc::clone(&state);
+                    builder.on_before_task_poll(move |meta| state.before_poll(meta));
+                }
+                builder.on_after_task_poll(move |_| state.after_poll());
+            }
+        }
+        builder
+    }
+}
+
+impl Default for RuntimeTracing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+thread_local! {
+    /// Whether the current thread is counted as a busy worker.
+    static BUSY: Cell<bool> = const { Cell::new(false) };
+    /// Whether the current thread has an open busy section.
+    static BUSY_SECTION: Cell<bool> = const { Cell::new(false) };
+    /// Whether the current thread has an open poll section.
+    #[cfg(tokio_unstable)]
+    static POLLING: Cell<bool> = const { Cell::new(false) };
+    /// The section name for the tasks spawned at each location.
+    ///
+    /// This is kept separately by each thread, so that polling a task never needs a lock.
+    #[cfg(tokio_unstable)]
+    static TASK_NAMES: RefCell<HashMap<&'static Location<'static>, Rc<CStr>>> =
+        RefCell::new(HashMap::new());
+}
+
+#[derive(Debug)]
+struct State {
+    config: RuntimeTracing,
+    busy_workers: AtomicI64,
+    /// The async section of each live task which was spawned whilst tracing was enabled.
+    #[cfg(tokio_unstable)]
+    tasks: Mutex<HashMap<Id, Task>>,
+}
+
+#[cfg(tokio_unstable)]
+#[derive(Debug)]
+struct Task {
+    name: CString,
+    cookie: i32,
+}
+
+impl State {
+    fn trace(&self) -> &AndroidTrace {
+        &self.config.trace
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.trace().is_enabled().unwrap_or(false)
+    }
+
+    fn unpark(&self) {
+        if BUSY.with(|busy| busy.replace(true)) {
+            return;
+        }
+        let busy_workers = self.busy_workers.fetch_add(1, Ordering::Relaxed) + 1;
+        // Once the section has begun, we must end it, even if tracing is stopped in the meantime
+        if self.config.busy_sections && self.is_enabled() {
+            self.trace().begin_section(c"tokio worker busy");
+            BUSY_SECTION.with(|section| section.set(true));
+        }
+        self.update_counters(busy_workers);
+    }
+
+    fn park(&self) {
+        if !BUSY.with(|busy| busy.replace(false)) {
+            return;
+        }
+        let busy_workers = self.busy_workers.fetch_sub(1, Ordering::Relaxed) - 1;
+        if BUSY_SECTION.with(|section| section.replace(false)) {
+            self.trace().end_section();
+        }
+        self.update_counters(busy_workers);
+    }
+
+    fn update_counters(&self, busy_workers: i64) {
+        if !self.config.counters || !self.is_enabled() {
+            return;
+        }
+        let trace = self.trace();
+        trace.set_counter(c"tokio.busy_workers", busy_workers);
+        if let Ok(handle) = Handle::try_current() {
+            let metrics = handle.metrics();
+            let to_counter = |value: usize| i64::try_from(value).unwrap_or(i64::MAX);
+            trace.set_counter(c"tokio.alive_tasks", to_counter(metrics.num_alive_tasks()));
+            trace.set_counter(
+                c"tokio.global_queue_depth",
+                to_counter(metrics.global_queue_depth()),
+            );
+        }
+    }
+
+    /// The section name of the task with `meta`, which is only created once for each location on each thread.
+    #[cfg(tokio_unstable)]
+    fn task_name(meta: &TaskMeta<'_>) -> Rc<CStr> {
+        let location = meta.spawned_at();
+        let create = || {
+            let name = format!("task {}:{}", location.file(), location.line());
+            Rc::from(CString::new(name.replace('\0', "\\0")).unwrap_or_default())
+        };
+        TASK_NAMES
+            .try_with(|names| Rc::clone(names.borrow_mut().entry(location).or_insert_with(create)))
+            .unwrap_or_else(|_| create())
+    }
+
+    #[cfg(tokio_unstable)]
+    fn task_spawned(&self, meta: &TaskMeta<'_>) {
+        let trace = self.trace();
+        if !trace.could_use_api_level_29() || !self.is_enabled() {
+            return;
+        }
+        let name = CString::from(&*Self::task_name(meta));
+        let cookie = CookieAllocator::global().allocate(&name);
+        trace.begin_async_section(&name, cookie);
+        self.tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(meta.id(), Task { name, cookie });
+    }
+
+    #[cfg(tokio_unstable)]
+    fn task_terminated(&self, meta: &TaskMeta<'_>) {
+        let task = self
+            .tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&meta.id());
+        if let Some(Task { name, cookie }) = task {
+            self.trace().end_async_section(&name, cookie);
+            CookieAllocator::global().free(&name, cookie);
+        }
+    }
+
+    #[cfg(tokio_unstable)]
+    fn before_poll(&self, meta: &TaskMeta<'_>) {
+        if !self.is_enabled() {
+            return;
+        }
+        POLLING.with(|polling| polling.set(true));
+        self.trace().begin_section(&Self::task_name(meta));
+    }
+
+    #[cfg(tokio_unstable)]
+    fn after_poll(&self) {
+        if POLLING.with(|polling| polling.replace(false)) {
+            self.trace().end_section();
+        }
+    }
+}
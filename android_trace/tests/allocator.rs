@@ -0,0 +1,41 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Tests for `TracingAllocator` as the global allocator, which needs its own test binary.
+
+#![cfg(all(feature = "recording", not(target_os = "android")))]
+
+use std::{alloc::System, time::Duration};
+
+use android_trace::{
+    allocator::{thread_allocated_bytes, TracingAllocator},
+    recording::{self, Event},
+};
+
+#[global_allocator]
+static ALLOCATOR: TracingAllocator<System> =
+    TracingAllocator::new(System).with_update_interval(Duration::ZERO);
+
+#[test]
+fn allocating_while_updating_counters_does_not_reenter() {
+    recording::set_enabled(true);
+    let before = thread_allocated_bytes();
+    let value = Box::new(0_u64);
+    let allocated = thread_allocated_bytes() - before;
+    let events = recording::take_events();
+    recording::set_enabled(false);
+    drop(value);
+
+    assert!(
+        allocated > 8,
+        "Recording the counters should allocate, re-entering the allocator"
+    );
+    assert!(
+        matches!(
+            &events[..],
+            [Event::Counter(live_bytes, _), Event::Counter(allocs, _)]
+                if live_bytes == "heap.live_bytes" && allocs == "heap.allocs"
+        ),
+        "Allocations made whilst updating the counters shouldn't update them again, got {events:?}"
+    );
+}
@@ -1,16 +1,20 @@
 // Copyright 2024 the Android Trace Authors
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use std::ffi::CString;
-
-use android_trace::AndroidTrace;
-use tracing::span;
-use tracing_subscriber::{
-    fmt::{
-        format::{DefaultFields, Writer},
-        FormatFields,
-    },
-    registry::LookupSpan,
+use std::{
+    ffi::{CStr, CString},
+    sync::Arc,
+    thread::{self, ThreadId},
+};
+
+use android_trace::{cookie::CookieAllocator, AndroidTrace};
+use tracing::{span, subscriber::Interest, Metadata};
+use tracing_subscriber::registry::{Extensions, ExtensionsMut, LookupSpan};
+
+use crate::{
+    diagnostics::{Diagnostic, DiagnosticKind, Diagnostics, DiagnosticsSink, Report},
+    fields::{self, ATraceFields, LazyName, ReservedFields},
+    lanes::LanePool,
 };
 
 /// A [`tracing_subscriber::Layer`] which uses [`ATrace_beginAsyncSection`](AndroidTrace::begin_async_section)
@@ -25,11 +29,73 @@ use tracing_subscriber::{
 /// It is recommended to use this layer with a suitable [`tracing_subscriber::filter`] to only
 /// target your desired async tasks, as each async task name will have a different row in the
 /// currently existing UIs for Android Tracing, which can be unwiedly
+///
+/// ## Tracks
+///
+/// To make related spans share a row, the name of the async section can instead be chosen
+/// using the reserved `atrace.track` field:
+///
+/// ```no_run
+/// # let url = "";
+/// let span = tracing::info_span!("request", atrace.track = "network", url);
+/// ```
+///
+/// The async section will then be named `network`.
+/// As async sections can only have a name, the usual name of the span (e.g. `request: url="..."`)
+/// is instead recorded as a zero-length section on the current thread, each time the async section begins.
+/// Fields starting with `atrace.` are never included in the names of sections.
+///
+/// ## Lanes
+///
+/// Alternatively, [`with_lanes`](Self::with_lanes) can be used to share a small set of rows
+/// between all spans which don't have a track.
+/// Each time an async section begins, it is placed in the first row which isn't in use.
+/// As with tracks, the name of the span is recorded as a zero-length section on the current thread.
+///
+/// Spans are shown whenever they are entered whilst tracing is enabled (or, in
+/// [`AsyncSectionMode::Lifetime`], if tracing is enabled at any point whilst they are entered),
+/// even if they were created before tracing was started.
+/// The fields of a span are only formatted if tracing is enabled when it is created, so spans
+/// created before tracing was started are shown with only their name, unless
+/// [`with_fields_after_enable`](Self::with_fields_after_enable) is used.
+///
+/// Each section is given a cookie which is unique amongst the open sections with the same name,
+/// so spans with recycled [`Id`](span::Id)s will not be confused with each other.
+///
+/// By default, a section is begun each time a span is entered, and ended when it is exited.
+/// See [`AsyncSectionMode`] for the alternatives.
 #[derive(Debug)]
 pub struct AndroidTraceAsyncLayer {
     trace: AndroidTrace,
-    fmt_fields: DefaultFields,
+    fmt_fields: ATraceFields,
     could_use_api_level_29: bool,
+    cookies: &'static CookieAllocator,
+    mode: AsyncSectionMode,
+    lanes: Option<LanePool>,
+    diagnostics: Diagnostics,
+    fields_after_enable: bool,
+}
+
+/// When an [`AndroidTraceAsyncLayer`] begins and ends the async section for a span.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum AsyncSectionMode {
+    /// Begin a section each time the span is entered, and end it when the span is exited.
+    ///
+    /// If a span is entered again before it is exited (such as on another thread), each entry
+    /// has its own section, with its own cookie (and lane, if [lanes](AndroidTraceAsyncLayer::with_lanes) are used).
+    /// An exit ends the section of the most recent entry on the same thread.
+    ///
+    /// This means that a future instrumented with a span will be shown as one section per poll.
+    #[default]
+    PerEnter,
+    /// Begin a section when the span is created, and end it when the span is closed.
+    ///
+    /// This means that a future instrumented with a span will be shown as a single section
+    /// covering its whole lifetime.
+    /// An [`AndroidTraceLayer`](crate::AndroidTraceLayer) can be used alongside this to also show
+    /// the individual polls.
+    Lifetime,
 }
 
 impl AndroidTraceAsyncLayer {
@@ -47,10 +113,87 @@ impl AndroidTraceAsyncLayer {
         let could_use_api_level_29 = trace.could_use_api_level_29();
         Self {
             trace,
-            fmt_fields: DefaultFields::new(),
+            fmt_fields: ATraceFields::new(),
             could_use_api_level_29,
+            cookies: CookieAllocator::global(),
+            mode: AsyncSectionMode::default(),
+            lanes: None,
+            diagnostics: Diagnostics::new(),
+            fields_after_enable: false,
         }
     }
+
+    /// Report problems encountered by this layer to `sink`, instead of to logcat.
+    ///
+    /// See [`DiagnosticsSink`] for an example.
+    #[must_use]
+    pub fn with_diagnostics_sink(mut self, sink: impl DiagnosticsSink) -> Self {
+        self.diagnostics.set_sink(Arc::new(sink));
+        self
+    }
+
+    /// The number of problems of the given kind which this layer has encountered.
+    pub fn diagnostic_count(&self, kind: DiagnosticKind) -> u64 {
+        self.diagnostics.count(kind)
+    }
+
+    /// Report problems to `sink`, sharing it with other layers.
+    pub(crate) fn set_diagnostics_sink(&mut self, sink: Arc<dyn DiagnosticsSink>) {
+        self.diagnostics.set_sink(sink);
+    }
+
+    /// Set when the async section for each span is begun and ended.
+    ///
+    /// ```no_run
+    /// # use tracing_subscriber::prelude::*;
+    /// use tracing_android_trace::{AndroidTraceAsyncLayer, AsyncSectionMode};
+    ///
+    /// tracing_subscriber::registry()
+    ///     .with(AndroidTraceAsyncLayer::new().with_mode(AsyncSectionMode::Lifetime))
+    ///     .try_init()
+    ///     .unwrap();
+    /// ```
+    #[must_use]
+    pub fn with_mode(mut self, mode: AsyncSectionMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Whether to format the fields of spans which are created whilst tracing is disabled.
+    ///
+    /// By default, spans created whilst tracing is disabled are shown with only their name if
+    /// they are entered once tracing is enabled, so that creating a span is cheap whilst not tracing.
+    /// Enabling this shows their fields, at the cost of formatting the fields of every span.
+    #[must_use]
+    pub fn with_fields_after_enable(mut self, fields_after_enable: bool) -> Self {
+        self.fields_after_enable = fields_after_enable;
+        self
+    }
+
+    /// Place the async sections of spans without an `atrace.track` field into a pool of `count`
+    /// rows, named `{prefix} 0` to `{prefix} {count - 1}`.
+    ///
+    /// Each section uses the first row which isn't currently in use.
+    /// If all rows are in use, the section is shown in its own row, as if lanes were not enabled.
+    ///
+    /// ```no_run
+    /// # use tracing_subscriber::prelude::*;
+    /// use tracing_android_trace::AndroidTraceAsyncLayer;
+    ///
+    /// tracing_subscriber::registry()
+    ///     .with(AndroidTraceAsyncLayer::new().with_lanes("rust async", 8))
+    ///     .try_init()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If `prefix` contains a null byte.
+    #[must_use]
+    pub fn with_lanes(mut self, prefix: &str, count: usize) -> Self {
+        self.lanes = Some(LanePool::new(prefix, count));
+        self
+    }
 }
 
 impl Default for AndroidTraceAsyncLayer {
@@ -61,14 +204,148 @@ impl Default for AndroidTraceAsyncLayer {
 
 #[derive(Debug)]
 pub(crate) struct ATraceExtensionAsync {
-    name: CString,
+    /// The full name of the span.
+    name: LazyName,
+    /// The track given using the `atrace.track` field, if any.
+    track: Option<CString>,
+    /// The async sections which are currently open for this span.
+    ///
+    /// In [`AsyncSectionMode::PerEnter`], there is one for each entry of the span (whilst tracing was enabled).
+    open: Vec<OpenSection>,
+}
+
+#[derive(Debug)]
+struct OpenSection {
+    /// The lane this section is in, if it was given one.
+    lane: Option<usize>,
     cookie: i32,
+    /// The thread which entered the span to begin this section.
+    thread: ThreadId,
+}
+
+impl AndroidTraceAsyncLayer {
+    /// Whether this layer could ever record anything on this device.
+    pub(crate) fn is_supported(&self) -> bool {
+        self.could_use_api_level_29
+    }
+
+    /// Whether this layer can currently record anything.
+    fn is_enabled(&self) -> bool {
+        self.could_use_api_level_29 && self.trace.is_enabled().unwrap_or(false)
+    }
+
+    /// Capture the fields of a new span, if they might be needed for its name.
+    pub(crate) fn capture(&self, attrs: &span::Attributes<'_>) -> Option<LazyName> {
+        if !self.fields_after_enable && !self.is_enabled() {
+            return None;
+        }
+        Some(self.fmt_fields.capture(attrs, &self.diagnostics))
+    }
+
+    /// Record that the span with `extensions` should be traced, with the given name.
+    pub(crate) fn insert_extension(
+        &self,
+        name: LazyName,
+        reserved: ReservedFields,
+        attrs: &span::Attributes<'_>,
+        extensions: &mut ExtensionsMut<'_>,
+    ) {
+        let track = reserved.track.and_then(|track| match CString::new(track) {
+            Ok(track) => Some(track),
+            Err(_) => {
+                self.diagnostics.report(Diagnostic::new(
+                    DiagnosticKind::NullByteInTrack,
+                    attrs.metadata(),
+                ));
+                None
+            }
+        });
+        let mut ext = ATraceExtensionAsync {
+            name,
+            track,
+            open: Vec::new(),
+        };
+        if self.mode == AsyncSectionMode::Lifetime && self.is_enabled() {
+            // Matches the call in `on_close`
+            self.begin(&mut ext, attrs.metadata());
+        }
+        extensions.insert::<ATraceExtensionAsync>(ext);
+    }
+
+    /// Whether the span with `extensions` is recorded by this layer.
+    pub(crate) fn has_extension(extensions: &Extensions<'_>) -> bool {
+        extensions.get::<ATraceExtensionAsync>().is_some()
+    }
+
+    /// Begin a new async section for `ext`, the span with `metadata`.
+    fn begin(&self, ext: &mut ATraceExtensionAsync, metadata: &'static Metadata<'static>) {
+        let Some(full_name) = ext.name.get(metadata, &self.diagnostics) else {
+            return;
+        };
+        let lane = if ext.track.is_none() {
+            self.lanes.as_ref().and_then(LanePool::acquire)
+        } else {
+            None
+        };
+        let name = match (lane, &ext.track) {
+            (Some(lane), _) => self
+                .lanes
+                .as_ref()
+                .expect("Can only have a lane if lanes are enabled")
+                .name(lane),
+            (None, Some(track)) => track,
+            (None, None) => full_name,
+        };
+        // Freed in `end`
+        let cookie = self.cookies.allocate(name);
+        if ext.track.is_some() || lane.is_some() {
+            // The section name doesn't describe the span, so record the span's name on this thread
+            self.trace.begin_section(full_name);
+            self.trace.end_section();
+        }
+        self.trace.begin_async_section(name, cookie);
+        ext.open.push(OpenSection {
+            lane,
+            cookie,
+            thread: thread::current().id(),
+        });
+    }
+
+    /// End the async section at `index` in the open sections of `ext`.
+    fn end(&self, ext: &mut ATraceExtensionAsync, index: usize) {
+        let OpenSection { lane, cookie, .. } = ext.open.remove(index);
+        let name = section_name(ext, lane, self.lanes.as_ref());
+        // Matches the call in `begin`
+        self.trace.end_async_section(name, cookie);
+        self.cookies.free(name, cookie);
+        if let Some(lane) = lane {
+            self.lanes
+                .as_ref()
+                .expect("Can only have a lane if lanes are enabled")
+                .release(lane);
+        }
+    }
+}
+
+/// The name of the async section for the span with `ext`, which is in `lane`.
+fn section_name<'a>(
+    ext: &'a ATraceExtensionAsync,
+    lane: Option<usize>,
+    lanes: Option<&'a LanePool>,
+) -> &'a CStr {
+    if let Some(lane) = lane {
+        lanes
+            .expect("Can only have a lane if lanes are enabled")
+            .name(lane)
+    } else if let Some(track) = &ext.track {
+        track
+    } else {
+        ext.name
+            .ready()
+            .expect("The name was formatted when the section began")
+    }
 }
 
-#[allow(
-    clippy::print_stderr,
-    // reason = "tracing::warn could lead to an infinite loop inside the tracing layer"
-)]
 impl<S> tracing_subscriber::Layer<S> for AndroidTraceAsyncLayer
 where
     S: tracing::Subscriber + for<'a> LookupSpan<'a>,
@@ -79,46 +356,30 @@ where
         id: &span::Id,
         ctx: tracing_subscriber::layer::Context<'_, S>,
     ) {
-        if self.could_use_api_level_29 && self.trace.is_enabled().unwrap_or(false) {
-            let span = ctx.span(id).expect("Span not found, this is a bug");
-            let mut extensions = span.extensions_mut();
-            let mut name = String::from(attrs.metadata().name());
-            name.push_str(": ");
-            if self
-                .fmt_fields
-                .format_fields(Writer::new(&mut name), attrs)
-                .is_ok()
+        // Spans are recorded even if tracing is currently disabled, as tracing might be enabled
+        // before they are entered
+        if self.is_supported() {
+            let name = self.capture(attrs);
+            let reserved = ReservedFields::from_attributes(attrs);
+            // Spans whose fields weren't captured only need per-span data once their section begins,
+            // which avoids an allocation for such spans which aren't entered whilst tracing
+            if name.is_none()
+                && reserved.track.is_none()
+                && !(self.mode == AsyncSectionMode::Lifetime && self.is_enabled())
             {
-                let name = CString::new(name);
-                match name {
-                    Ok(name) => {
-                        #[allow(
-                            clippy::cast_possible_truncation,
-                            // reason = "The cookies have to be i32, but the available source is u64"
-                        )]
-                        let cookie = (id.into_u64() % u32::MAX as u64) as u32 as i32;
-                        extensions
-                            .insert::<ATraceExtensionAsync>(ATraceExtensionAsync { name, cookie });
-                    }
-                    // This error printing style is based on the precedent of tracing_subscriber.
-                    // Using `tracing::warn` or similar could lead to an infinite loop.
-                    Err(e) => eprintln!(
-                        concat!(
-                            "[tracing_android_trace] Unable to format the following ",
-                            "span due to a null byte ({:?}), ignoring: {:?}",
-                        ),
-                        e, attrs
-                    ),
-                }
-            } else {
-                eprintln!(
-                    "[tracing_android_trace] Unable to format the following event, ignoring: {:?}",
-                    attrs
-                );
+                return;
             }
+            let span = ctx.span(id).expect("Span not found, this is a bug");
+            let name = name.unwrap_or_else(LazyName::without_fields);
+            self.insert_extension(name, reserved, attrs, &mut span.extensions_mut());
         }
     }
 
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        fields::register_callsite(metadata);
+        Interest::always()
+    }
+
     fn on_record(
         &self,
         _span: &span::Id,
@@ -143,18 +404,61 @@ where
 
     fn on_enter(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
         let span = ctx.span(id).expect("Span not found, this is a bug");
-        let extensions = span.extensions();
-        if let Some(ext) = extensions.get::<ATraceExtensionAsync>() {
-            self.trace.begin_async_section(&ext.name, ext.cookie);
+        let mut extensions = span.extensions_mut();
+        if extensions.get_mut::<ATraceExtensionAsync>().is_none() {
+            // Spans whose fields weren't captured are only given an extension once needed
+            if !self.is_enabled() {
+                return;
+            }
+            extensions.insert(ATraceExtensionAsync {
+                name: LazyName::without_fields(),
+                track: None,
+                open: Vec::new(),
+            });
+        }
+        let ext = extensions
+            .get_mut::<ATraceExtensionAsync>()
+            .expect("Inserted above if missing");
+        match self.mode {
+            AsyncSectionMode::PerEnter if self.is_enabled() => {
+                self.begin(ext, span.metadata());
+            }
+            // If tracing was disabled when this span was created, its section begins
+            // the first time it is entered whilst tracing is enabled
+            AsyncSectionMode::Lifetime if ext.open.is_empty() && self.is_enabled() => {
+                self.begin(ext, span.metadata());
+            }
+            _ => {}
         }
     }
 
     fn on_exit(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if self.mode != AsyncSectionMode::PerEnter {
+            return;
+        }
         let span = ctx.span(id).expect("Span not found, this is a bug");
-        let extensions = span.extensions();
-        if let Some(ext) = extensions.get::<ATraceExtensionAsync>() {
-            // Matches the call in `on_enter`
-            self.trace.end_async_section(&ext.name, ext.cookie);
+        let mut extensions = span.extensions_mut();
+        if let Some(ext) = extensions.get_mut::<ATraceExtensionAsync>() {
+            // Matches the call in `on_enter` on this thread.
+            // If the span is exited on a different thread to the one which entered it, its
+            // section is instead ended when the span is closed
+            let current = thread::current().id();
+            if let Some(index) = ext.open.iter().rposition(|open| open.thread == current) {
+                self.end(ext, index);
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let span = ctx.span(&id).expect("Span not found, this is a bug");
+        let mut extensions = span.extensions_mut();
+        if let Some(ext) = extensions.get_mut::<ATraceExtensionAsync>() {
+            // Matches the call in `on_new_span` in `AsyncSectionMode::Lifetime`.
+            // In `AsyncSectionMode::PerEnter`, the sections should already have been ended
+            // in `on_exit`, but make sure that cookies and lanes are always released
+            while !ext.open.is_empty() {
+                self.end(ext, ext.open.len() - 1);
+            }
         }
     }
 }
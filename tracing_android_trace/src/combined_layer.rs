@@ -0,0 +1,232 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::{fmt::Debug, sync::Arc};
+
+use tracing::{
+    span::{self, Id},
+    subscriber::Interest,
+    Metadata,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use crate::{
+    diagnostics::{DiagnosticKind, DiagnosticsSink},
+    fields::{self, LazyName, ReservedFields},
+    AndroidTraceAsyncLayer, AndroidTraceLayer, SectionCloser,
+};
+
+type AsyncPredicate = dyn Fn(&Metadata<'_>) -> bool + Send + Sync;
+
+/// A [`tracing_subscriber::Layer`] which records each span using either an [`AndroidTraceLayer`]
+/// or an [`AndroidTraceAsyncLayer`], chosen per span.
+///
+/// A span is recorded as an async section if it has the reserved `atrace.async` field set to `true`,
+/// or if it matches the predicate given to [`with_async_predicate`](Self::with_async_predicate).
+/// As `async` is a keyword, the field's name needs to be written as a string literal, such as
+/// `tracing::info_span!("download", "atrace.async" = true)`.
+/// All other spans are recorded as thread-matched sections.
+/// If async sections are not supported on this device, all spans are recorded as thread-matched sections.
+///
+/// This avoids needing to keep two sets of filters (one for each layer) in sync, and means
+/// that the name of each span only needs to be formatted once.
+///
+/// ## Usage
+///
+/// ```no_run
+/// # use tracing_subscriber::prelude::*;
+/// use tracing_android_trace::AndroidTraceCombinedLayer;
+///
+/// tracing_subscriber::registry()
+///     .with(
+///         AndroidTraceCombinedLayer::new()
+///             .with_async_predicate(|metadata| metadata.target().starts_with("my_app::net")),
+///     )
+///     .try_init()
+///     .unwrap();
+///
+/// // Recorded using `ATrace_beginAsyncSection`
+/// let download = tracing::info_span!("download", "atrace.async" = true);
+/// // Recorded using `ATrace_beginSection`
+/// let decode = tracing::info_span!("decode");
+/// ```
+pub struct AndroidTraceCombinedLayer {
+    sync_layer: AndroidTraceLayer,
+    async_layer: AndroidTraceAsyncLayer,
+    is_async: Option<Box<AsyncPredicate>>,
+}
+
+impl AndroidTraceCombinedLayer {
+    /// Create a `AndroidTraceCombinedLayer`
+    pub fn new() -> Self {
+        Self::with_layers(AndroidTraceLayer::new(), AndroidTraceAsyncLayer::new())
+    }
+
+    /// Create a `AndroidTraceCombinedLayer` which uses the given layers to record spans.
+    ///
+    /// This allows the layers to be configured, for example using
+    /// [`AndroidTraceAsyncLayer::with_mode`].
+    pub fn with_layers(sync_layer: AndroidTraceLayer, async_layer: AndroidTraceAsyncLayer) -> Self {
+        Self {
+            sync_layer,
+            async_layer,
+            is_async: None,
+        }
+    }
+
+    /// Also record spans whose metadata matches `predicate` as async sections.
+    ///
+    /// Spans with the `atrace.async` field set to `false` are never recorded as
+    /// async sections, even if they match `predicate`.
+    #[must_use]
+    pub fn with_async_predicate(
+        mut self,
+        predicate: impl Fn(&Metadata<'_>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.is_async = Some(Box::new(predicate));
+        self
+    }
+
+    /// Report problems encountered by this layer to `sink`, instead of to logcat.
+    ///
+    /// See [`DiagnosticsSink`] for an example.
+    #[must_use]
+    pub fn with_diagnostics_sink(mut self, sink: impl DiagnosticsSink) -> Self {
+        let sink: Arc<dyn DiagnosticsSink> = Arc::new(sink);
+        self.sync_layer.set_diagnostics_sink(Arc::clone(&sink));
+        self.async_layer.set_diagnostics_sink(sink);
+        self
+    }
+
+    /// The number of problems of the given kind which this layer has encountered.
+    pub fn diagnostic_count(&self, kind: DiagnosticKind) -> u64 {
+        self.sync_layer.diagnostic_count(kind) + self.async_layer.diagnostic_count(kind)
+    }
+
+    /// Get a [`SectionCloser`], which can end the thread-matched sections left open by this layer
+    /// if a thread panics or exits whilst spans are still entered.
+    pub fn section_closer(&self) -> SectionCloser {
+        self.sync_layer.section_closer()
+    }
+}
+
+impl Default for AndroidTraceCombinedLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for AndroidTraceCombinedLayer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AndroidTraceCombinedLayer")
+            .field("sync_layer", &self.sync_layer)
+            .field("async_layer", &self.async_layer)
+            .field("is_async", &self.is_async.as_ref().map(|_| "<predicate>"))
+            .finish()
+    }
+}
+
+impl<S> Layer<S> for AndroidTraceCombinedLayer
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !self.sync_layer.is_supported() {
+            return;
+        }
+        let reserved = ReservedFields::from_attributes(attrs);
+        let is_async = reserved.is_async.unwrap_or_else(|| {
+            self.is_async
+                .as_ref()
+                .is_some_and(|predicate| predicate(attrs.metadata()))
+        });
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        if is_async && self.async_layer.is_supported() {
+            let name = self
+                .async_layer
+                .capture(attrs)
+                .unwrap_or_else(LazyName::without_fields);
+            // Async spans always need the extension, so that `is_async_span` can find them
+            self.async_layer
+                .insert_extension(name, reserved, attrs, &mut span.extensions_mut());
+        } else if let Some(name) = self.sync_layer.capture(attrs) {
+            self.sync_layer.record_new_span(name, &span);
+        }
+    }
+
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        fields::register_callsite(metadata);
+        Interest::always()
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if self.is_async_span(id, &ctx) {
+            self.async_layer.on_enter(id, ctx);
+        } else {
+            self.sync_layer.on_enter(id, ctx);
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if self.is_async_span(id, &ctx) {
+            self.async_layer.on_exit(id, ctx);
+        } else {
+            self.sync_layer.on_exit(id, ctx);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if self.is_async_span(&id, &ctx) {
+            self.async_layer.on_close(id, ctx);
+        } else {
+            self.sync_layer.on_close(id, ctx);
+        }
+    }
+}
+
+impl AndroidTraceCombinedLayer {
+    /// Whether the span with `id` is recorded by the async layer.
+    ///
+    /// Spans which are recorded by neither layer are given to the sync layer, as it
+    /// needs to know about every exiting span to keep its stack consistent.
+    fn is_async_span<S>(&self, id: &Id, ctx: &Context<'_, S>) -> bool
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        let extensions = span.extensions();
+        AndroidTraceAsyncLayer::has_extension(&extensions)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use android_trace::recording::{self, Event};
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn async_field_chooses_async_section() {
+        recording::set_enabled(true);
+        let subscriber = tracing_subscriber::registry().with(AndroidTraceCombinedLayer::new());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("download", "atrace.async" = true).in_scope(|| {});
+            tracing::info_span!("decode").in_scope(|| {});
+        });
+        let events = recording::take_events();
+        let [Event::BeginAsync(_, cookie), ..] = events[..] else {
+            panic!("The span with `atrace.async` should be async, got {events:?}");
+        };
+        assert_eq!(
+            events,
+            [
+                Event::BeginAsync("download: ".into(), cookie),
+                Event::EndAsync("download: ".into(), cookie),
+                Event::Begin("decode: ".into()),
+                Event::End,
+            ],
+            "Only the span with `atrace.async` should be async"
+        );
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    ffi::CString,
+    fmt::{self, Debug, Display},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use android_trace::logcat::{self, LogPriority};
+use tracing::{callsite::Identifier, Metadata};
+
+/// A problem encountered by one of the layers in this crate, such as a span which couldn't be recorded.
+///
+/// These are reported to a [`DiagnosticsSink`], rather than using `tracing` itself, as that could lead
+/// to an infinite loop inside the layer.
+#[derive(Debug, Clone, Copy)]
+pub struct Diagnostic {
+    kind: DiagnosticKind,
+    metadata: &'static Metadata<'static>,
+}
+
+/// The kind of a [`Diagnostic`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DiagnosticKind {
+    /// The name of a span contained a null byte, so the span was not recorded.
+    NullByteInName,
+    /// The `atrace.track` field of a span contained a null byte, so the field was ignored.
+    NullByteInTrack,
+    /// The fields of a span could not be formatted, so the span was not recorded.
+    FormatError,
+    /// A span was exited more times than the layer recorded it being entered.
+    UnbalancedExit,
+    /// The layer's record of open sections was inconsistent with the spans, which is a bug in this crate.
+    UnexpectedStackState,
+}
+
+impl DiagnosticKind {
+    const COUNT: usize = 5;
+
+    fn index(self) -> usize {
+        match self {
+            Self::NullByteInName => 0,
+            Self::NullByteInTrack => 1,
+            Self::FormatError => 2,
+            Self::UnbalancedExit => 3,
+            Self::UnexpectedStackState => 4,
+        }
+    }
+}
+
+impl Diagnostic {
+    pub(crate) fn new(kind: DiagnosticKind, metadata: &'static Metadata<'static>) -> Self {
+        Self { kind, metadata }
+    }
+
+    /// The kind of problem.
+    pub fn kind(&self) -> DiagnosticKind {
+        self.kind
+    }
+
+    /// The metadata of the span which caused the problem.
+    pub fn metadata(&self) -> &'static Metadata<'static> {
+        self.metadata
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self.kind {
+            DiagnosticKind::NullByteInName => {
+                "Unable to record span due to a null byte in its name"
+            }
+            DiagnosticKind::NullByteInTrack => {
+                "Unable to use the track of span due to a null byte, ignoring"
+            }
+            DiagnosticKind::FormatError => "Unable to format the fields of span",
+            DiagnosticKind::UnbalancedExit => "Span was exited more times than it was entered",
+            DiagnosticKind::UnexpectedStackState => {
+                "Unexpectedly had span in stack without a section name"
+            }
+        };
+        write!(
+            f,
+            "[tracing_android_trace] {message}: {} ({})",
+            self.metadata.name(),
+            self.metadata.target()
+        )
+    }
+}
+
+/// Receives the [`Diagnostic`]s from the layers in this crate.
+///
+/// Diagnostics are reported once the layer has released its own state, so implementations may use
+/// `tracing`, as long as that can't cause further diagnostics, which would lead to an infinite loop.
+///
+/// ```no_run
+/// # use tracing_subscriber::prelude::*;
+/// use tracing_android_trace::{AndroidTraceLayer, Diagnostic, DiagnosticsSink};
+///
+/// #[derive(Debug)]
+/// struct Telemetry;
+///
+/// impl DiagnosticsSink for Telemetry {
+///     fn report(&self, diagnostic: &Diagnostic) {
+///         // Forward to your telemetry system
+///     }
+/// }
+///
+/// tracing_subscriber::registry()
+///     .with(AndroidTraceLayer::new().with_diagnostics_sink(Telemetry))
+///     .try_init()
+///     .unwrap();
+/// ```
+pub trait DiagnosticsSink: Debug + Send + Sync + 'static {
+    /// Report a problem.
+    fn report(&self, diagnostic: &Diagnostic);
+}
+
+/// The default [`DiagnosticsSink`], which writes diagnostics to logcat with the tag
+/// `tracing_android_trace`.
+///
+/// Only the first diagnostic of each kind from each callsite is written, so that a span which is
+/// repeatedly mishandled doesn't flood logcat.
+#[derive(Debug, Default)]
+pub struct LogcatSink {
+    reported: Mutex<HashSet<(DiagnosticKind, Identifier)>>,
+}
+
+impl LogcatSink {
+    /// Create a sink which writes to logcat.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl DiagnosticsSink for LogcatSink {
+    fn report(&self, diagnostic: &Diagnostic) {
+        let first = self
+            .reported
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert((diagnostic.kind, diagnostic.metadata.callsite()));
+        if !first {
+            return;
+        }
+        // Span names and targets are string literals, so are very unlikely to contain a null byte
+        if let Ok(message) = CString::new(diagnostic.to_string()) {
+            logcat::write(LogPriority::Warn, c"tracing_android_trace", &message);
+        }
+    }
+}
+
+/// Somewhere which a layer can report a [`Diagnostic`] to.
+pub(crate) trait Report {
+    fn report(&self, diagnostic: Diagnostic);
+}
+
+/// The diagnostics sink of a layer, along with the number of diagnostics of each kind.
+#[derive(Debug)]
+pub(crate) struct Diagnostics {
+    sink: Arc<dyn DiagnosticsSink>,
+    counts: [AtomicU64; DiagnosticKind::COUNT],
+}
+
+impl Diagnostics {
+    pub(crate) fn new() -> Self {
+        Self {
+            sink: Arc::new(LogcatSink::new()),
+            counts: Default::default(),
+        }
+    }
+
+    pub(crate) fn set_sink(&mut self, sink: Arc<dyn DiagnosticsSink>) {
+        self.sink = sink;
+    }
+
+    /// Collect diagnostics, which are only reported once the returned value is dropped.
+    pub(crate) fn deferred(&self) -> DeferredDiagnostics<'_> {
+        DeferredDiagnostics {
+            diagnostics: self,
+            pending: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn count(&self, kind: DiagnosticKind) -> u64 {
+        self.counts[kind.index()].load(Ordering::Relaxed)
+    }
+}
+
+impl Report for Diagnostics {
+    fn report(&self, diagnostic: Diagnostic) {
+        self.counts[diagnostic.kind.index()].fetch_add(1, Ordering::Relaxed);
+        self.sink.report(&diagnostic);
+    }
+}
+
+/// Diagnostics which are passed to the sink when this is dropped.
+///
+/// This is used whilst a layer holds the state of the current thread, as a sink which uses `tracing`
+/// would re-enter the layer.
+/// It should be created before that state is borrowed, so that it is dropped afterwards.
+#[derive(Debug)]
+pub(crate) struct DeferredDiagnostics<'a> {
+    diagnostics: &'a Diagnostics,
+    pending: RefCell<Vec<Diagnostic>>,
+}
+
+impl Report for DeferredDiagnostics<'_> {
+    fn report(&self, diagnostic: Diagnostic) {
+        self.pending.borrow_mut().push(diagnostic);
+    }
+}
+
+impl Drop for DeferredDiagnostics<'_> {
+    fn drop(&mut self) {
+        for diagnostic in self.pending.get_mut().drain(..) {
+            self.diagnostics.report(diagnostic);
+        }
+    }
+}
@@ -0,0 +1,267 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    fmt,
+    sync::{OnceLock, PoisonError, RwLock},
+};
+
+use crate::diagnostics::{Diagnostic, DiagnosticKind, Report};
+use tracing::{
+    callsite::Identifier,
+    field::{Field, Visit},
+    span, Metadata,
+};
+use tracing_subscriber::{
+    field::{MakeVisitor, VisitFmt, VisitOutput},
+    fmt::{
+        format::{DefaultFields, DefaultVisitor, Writer},
+        FormatFields,
+    },
+};
+
+/// The prefix of span fields which control how this crate records a span,
+/// rather than being part of the span's name.
+pub(crate) const RESERVED_PREFIX: &str = "atrace.";
+
+/// The field used to choose the name of the async section for a span,
+/// so that related spans can share a single row in the trace.
+pub(crate) const TRACK_FIELD: &str = "atrace.track";
+
+/// The field used to ask an [`AndroidTraceCombinedLayer`](crate::AndroidTraceCombinedLayer)
+/// to record a span as an async section.
+///
+/// As `async` is a keyword, this needs to be written as a string literal in the `tracing` macros.
+pub(crate) const ASYNC_FIELD: &str = "atrace.async";
+
+/// Formats fields in the same way as [`DefaultFields`], except that fields
+/// starting with [`RESERVED_PREFIX`] are skipped.
+#[derive(Debug, Default)]
+pub(crate) struct ATraceFields {
+    inner: DefaultFields,
+}
+
+impl ATraceFields {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Capture the fields of a new span, so that its name can be formatted once it is needed.
+    ///
+    /// The values of a span's fields are only available when it is created, so they need to be
+    /// formatted immediately.
+    /// Spans without any (non-reserved) fields don't need any work to be done here, and
+    /// will use the name from [`callsite_name`] if it is available.
+    /// This is the only place which looks up the name of a callsite, so that entering a span
+    /// only needs its own extension.
+    pub(crate) fn capture(
+        &self,
+        attrs: &span::Attributes<'_>,
+        diagnostics: &impl Report,
+    ) -> LazyName {
+        if let Some(name) = callsite_name(attrs.metadata()) {
+            return LazyName::Static(name);
+        }
+        let mut fields = String::new();
+        if has_unreserved_fields(attrs.metadata())
+            && self.format_fields(Writer::new(&mut fields), attrs).is_err()
+        {
+            diagnostics.report(Diagnostic::new(
+                DiagnosticKind::FormatError,
+                attrs.metadata(),
+            ));
+            return LazyName::Invalid;
+        }
+        LazyName::Pending(fields)
+    }
+}
+
+/// The name used for the sections of a span, which is only created once it is needed.
+#[derive(Debug)]
+pub(crate) enum LazyName {
+    /// The formatted fields of the span, before the name has been needed.
+    Pending(String),
+    /// The name, once it has been needed.
+    Ready(CString),
+    /// The name shared by all spans from this callsite.
+    Static(&'static CStr),
+    /// The name could not be formatted. This has already been reported.
+    Invalid,
+}
+
+impl LazyName {
+    /// The name for a span whose fields were not captured, which is just the name of the span.
+    pub(crate) fn without_fields() -> Self {
+        Self::Pending(String::new())
+    }
+
+    /// Get the name of the span with `metadata`, formatting it if this is the first time it is needed.
+    pub(crate) fn get(
+        &mut self,
+        metadata: &'static Metadata<'static>,
+        diagnostics: &impl Report,
+    ) -> Option<&CStr> {
+        if let Self::Pending(fields) = self {
+            let mut name = String::with_capacity(metadata.name().len() + 2 + fields.len());
+            name.push_str(metadata.name());
+            name.push_str(": ");
+            name.push_str(fields);
+            *self = match CString::new(name) {
+                Ok(name) => Self::Ready(name),
+                Err(_) => {
+                    diagnostics.report(Diagnostic::new(DiagnosticKind::NullByteInName, metadata));
+                    Self::Invalid
+                }
+            };
+        }
+        self.ready()
+    }
+
+    /// Get the name, if it has already been formatted.
+    pub(crate) fn ready(&self) -> Option<&CStr> {
+        match self {
+            Self::Ready(name) => Some(name),
+            Self::Static(name) => Some(name),
+            Self::Pending(_) | Self::Invalid => None,
+        }
+    }
+}
+
+impl<'a> MakeVisitor<Writer<'a>> for ATraceFields {
+    type Visitor = SkipReserved<DefaultVisitor<'a>>;
+
+    fn make_visitor(&self, target: Writer<'a>) -> Self::Visitor {
+        SkipReserved(self.inner.make_visitor(target))
+    }
+}
+
+/// A visitor which forwards all fields except the reserved ones to the inner visitor.
+#[derive(Debug)]
+pub(crate) struct SkipReserved<V>(V);
+
+fn is_reserved(field: &Field) -> bool {
+    field.name().starts_with(RESERVED_PREFIX)
+}
+
+fn has_unreserved_fields(metadata: &Metadata<'_>) -> bool {
+    metadata.fields().iter().any(|f| !is_reserved(&f))
+}
+
+/// The section names for callsites of spans without any (non-reserved) fields.
+///
+/// All spans from such a callsite have the same name, so the name only needs to be created once.
+/// These names are never freed, which is fine as there are a fixed number of callsites.
+/// This is shared between all layers, as they all use the same names.
+static CALLSITE_NAMES: OnceLock<RwLock<HashMap<Identifier, &'static CStr>>> = OnceLock::new();
+
+/// Create the shared section name for spans from the callsite with `metadata`, if it doesn't
+/// have any (non-reserved) fields.
+///
+/// Should be called from [`register_callsite`](tracing_subscriber::Layer::register_callsite).
+pub(crate) fn register_callsite(metadata: &'static Metadata<'static>) {
+    if !metadata.is_span() || has_unreserved_fields(metadata) {
+        return;
+    }
+    let names = CALLSITE_NAMES.get_or_init(Default::default);
+    let id = metadata.callsite();
+    if names
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .contains_key(&id)
+    {
+        return;
+    }
+    // Spans with a null byte in their name will be reported when they are entered
+    if let Ok(name) = CString::new(format!("{}: ", metadata.name())) {
+        names
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(id)
+            .or_insert_with(|| Box::leak(name.into_boxed_c_str()));
+    }
+}
+
+/// The shared section name for spans from the callsite with `metadata`, if it has been created.
+pub(crate) fn callsite_name(metadata: &Metadata<'_>) -> Option<&'static CStr> {
+    let names = CALLSITE_NAMES.get()?;
+    names
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&metadata.callsite())
+        .copied()
+}
+
+impl<V: Visit> Visit for SkipReserved<V> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if !is_reserved(field) {
+            self.0.record_str(field, value);
+        }
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        if !is_reserved(field) {
+            self.0.record_error(field, value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !is_reserved(field) {
+            self.0.record_debug(field, value);
+        }
+    }
+}
+
+impl<V: VisitOutput<fmt::Result>> VisitOutput<fmt::Result> for SkipReserved<V> {
+    fn finish(self) -> fmt::Result {
+        self.0.finish()
+    }
+}
+
+impl<V: VisitFmt> VisitFmt for SkipReserved<V> {
+    fn writer(&mut self) -> &mut dyn fmt::Write {
+        self.0.writer()
+    }
+}
+
+/// The values of the reserved fields of a span.
+#[derive(Debug, Default)]
+pub(crate) struct ReservedFields {
+    /// The value of [`TRACK_FIELD`].
+    pub(crate) track: Option<String>,
+    /// The value of [`ASYNC_FIELD`].
+    pub(crate) is_async: Option<bool>,
+}
+
+impl ReservedFields {
+    /// Read the reserved fields from the attributes of a new span.
+    pub(crate) fn from_attributes(attrs: &span::Attributes<'_>) -> Self {
+        let mut reserved = Self::default();
+        // Avoid visiting the values entirely in the common case
+        if attrs.metadata().fields().iter().any(|f| is_reserved(&f)) {
+            attrs.record(&mut reserved);
+        }
+        reserved
+    }
+}
+
+impl Visit for ReservedFields {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == ASYNC_FIELD {
+            self.is_async = Some(value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == TRACK_FIELD {
+            self.track = Some(value.to_owned());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == TRACK_FIELD {
+            self.track = Some(format!("{value:?}"));
+        }
+    }
+}
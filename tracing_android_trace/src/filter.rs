@@ -0,0 +1,127 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use android_trace::AndroidTrace;
+use tracing::{subscriber::Interest, Metadata};
+use tracing_subscriber::layer::{Context, Filter};
+
+/// Whether tracing was enabled the last time it was checked by any [`AndroidTraceFilter`].
+///
+/// This is global because the interest cache in `tracing` is global.
+static TRACE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// A [per-layer filter](tracing_subscriber::layer::Filter) which disables every callsite whilst
+/// Android tracing is not enabled.
+///
+/// When used to filter the layers from this crate, spans which are only used for Android tracing are
+/// never created whilst a trace is not being captured, so the only cost of such a span is
+/// checking the cached [`Interest`] of its callsite.
+/// Callsites which are also enabled by other layers are unaffected.
+///
+/// Android does not notify applications when tracing starts or stops, so the state needs to be
+/// checked using [`refresh`](Self::refresh).
+/// This will rebuild the `tracing` interest cache whenever tracing has been started or stopped.
+/// [`spawn_refresh_thread`](Self::spawn_refresh_thread) can be used to do this periodically.
+///
+/// Note that this means that spans created before tracing was started (or before the next refresh)
+/// are not shown, even if they are entered whilst tracing is enabled.
+/// Without this filter, the layers in this crate would show such spans.
+///
+/// ## Usage
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use tracing_subscriber::prelude::*;
+/// use tracing_android_trace::{AndroidTraceFilter, AndroidTraceLayer};
+///
+/// tracing_subscriber::registry()
+///     .with(AndroidTraceLayer::new().with_filter(AndroidTraceFilter::new()))
+///     .try_init()
+///     .unwrap();
+/// AndroidTraceFilter::spawn_refresh_thread(Duration::from_millis(500));
+/// ```
+#[derive(Debug, Clone)]
+pub struct AndroidTraceFilter {
+    trace: AndroidTrace,
+}
+
+impl AndroidTraceFilter {
+    /// Create a `AndroidTraceFilter`, checking whether tracing is currently enabled.
+    pub fn new() -> Self {
+        Self::with_trace(AndroidTrace::new_downlevel())
+    }
+
+    /// Create a `AndroidTraceFilter` from a pre-existing [`AndroidTrace`].
+    pub fn with_trace(trace: AndroidTrace) -> Self {
+        let filter = Self { trace };
+        TRACE_ENABLED.store(filter.is_trace_enabled(), Ordering::Relaxed);
+        filter
+    }
+
+    /// Check whether tracing has been started or stopped since the last check, and
+    /// rebuild the `tracing` interest cache if so.
+    ///
+    /// Returns whether tracing is currently enabled.
+    ///
+    /// This should not be called from within a span callback, as rebuilding the
+    /// interest cache calls into every subscriber.
+    pub fn refresh(&self) -> bool {
+        let enabled = self.is_trace_enabled();
+        if TRACE_ENABLED.swap(enabled, Ordering::Relaxed) != enabled {
+            tracing::callsite::rebuild_interest_cache();
+        }
+        enabled
+    }
+
+    /// Spawn a thread which calls [`refresh`](Self::refresh) every `period`.
+    ///
+    /// The thread runs until the process exits.
+    ///
+    /// # Panics
+    ///
+    /// If the thread could not be spawned.
+    pub fn spawn_refresh_thread(period: Duration) -> JoinHandle<()> {
+        let filter = Self::new();
+        thread::Builder::new()
+            .name("atrace-refresh".into())
+            .spawn(move || filter.refresh_forever(period))
+            .expect("Should be able to spawn the refresh thread")
+    }
+
+    fn refresh_forever(&self, period: Duration) -> ! {
+        loop {
+            self.refresh();
+            thread::sleep(period);
+        }
+    }
+
+    fn is_trace_enabled(&self) -> bool {
+        self.trace.is_enabled().unwrap_or(false)
+    }
+}
+
+impl Default for AndroidTraceFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Filter<S> for AndroidTraceFilter {
+    fn enabled(&self, _: &Metadata<'_>, _: &Context<'_, S>) -> bool {
+        TRACE_ENABLED.load(Ordering::Relaxed)
+    }
+
+    fn callsite_enabled(&self, _: &'static Metadata<'static>) -> Interest {
+        if TRACE_ENABLED.load(Ordering::Relaxed) {
+            Interest::always()
+        } else {
+            Interest::never()
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::{
+    ffi::{CStr, CString},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// A fixed set of async section names ("lanes"), each of which can be used by
+/// at most one span at a time.
+///
+/// Spans are given the first free lane, which is the greedy colouring of the interval graph
+/// of the spans.
+/// This keeps the number of rows in the trace small, even if many short-lived spans are used.
+#[derive(Debug)]
+pub(crate) struct LanePool {
+    names: Box<[CString]>,
+    in_use: Box<[AtomicBool]>,
+}
+
+impl LanePool {
+    /// Create a pool of `count` lanes, named `{prefix} 0` to `{prefix} {count - 1}`.
+    ///
+    /// # Panics
+    ///
+    /// If `prefix` contains a null byte.
+    pub(crate) fn new(prefix: &str, count: usize) -> Self {
+        let names = (0..count)
+            .map(|idx| {
+                CString::new(format!("{prefix} {idx}"))
+                    .expect("Lane prefix should not contain a null byte")
+            })
+            .collect();
+        let in_use = (0..count).map(|_| AtomicBool::new(false)).collect();
+        Self { names, in_use }
+    }
+
+    /// Claim the first lane which is not in use, if there is one.
+    ///
+    /// The lane should be returned using [`Self::release`].
+    pub(crate) fn acquire(&self) -> Option<usize> {
+        self.in_use.iter().position(|in_use| {
+            in_use
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        })
+    }
+
+    /// Return a lane previously claimed using [`Self::acquire`].
+    pub(crate) fn release(&self, lane: usize) {
+        let was_in_use = self.in_use[lane].swap(false, Ordering::Release);
+        debug_assert!(was_in_use, "Released a lane which wasn't in use");
+    }
+
+    /// The async section name of the given lane.
+    pub(crate) fn name(&self, lane: usize) -> &CStr {
+        &self.names[lane]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn first_free_lane_is_used() {
+        let lanes = LanePool::new("rust async", 2);
+        assert_eq!(lanes.acquire(), Some(0), "The first lane is free");
+        assert_eq!(lanes.acquire(), Some(1), "The second lane is free");
+        assert_eq!(lanes.acquire(), None, "All lanes are in use");
+        lanes.release(0);
+        assert_eq!(lanes.acquire(), Some(0), "The first lane was released");
+        assert_eq!(lanes.name(1), c"rust async 1", "Lanes use the prefix");
+    }
+}
@@ -8,6 +8,11 @@
 //! [`tracing_subscriber::Layer`]: tracing_subscriber::Layer
 //! [`AndroidTraceLayer`]: AndroidTraceLayer
 //! [`AndroidTraceAsyncLayer`]: AndroidTraceAsyncLayer
+//! [`AsyncSectionMode::Lifetime`]: AsyncSectionMode::Lifetime
+//! [`AndroidTraceCombinedLayer`]: AndroidTraceCombinedLayer
+//! [`AndroidTraceFilter`]: AndroidTraceFilter
+//! [`AndroidLogLayer`]: AndroidLogLayer
+//! [`TracedThreadPool`]: TracedThreadPool
 //! [`android_trace`]: android_trace
 // File links are not supported by rustdoc
 //! [LICENSE-APACHE]: https://github.com/linebender/android_trace/blob/main/LICENSE-APACHE
@@ -27,24 +32,53 @@
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
 #![forbid(unsafe_code)]
 
-#[cfg(not(target_os = "android"))]
+// The tests use the `recording` feature of android_trace, so can also run on the host
+#[cfg(not(any(target_os = "android", test)))]
 compile_error!(
     r#"tracing_android_trace only supports Android. If you are depending on it, ensure that it is within
 [target.'cfg(target_os = "android")'.dependencies]
 in your Cargo.toml"#
 );
 
-#[cfg(target_os = "android")]
+#[cfg(any(target_os = "android", test))]
 pub use android_trace;
 
-#[cfg(target_os = "android")]
+#[cfg(any(target_os = "android", test))]
 mod async_layer;
-#[cfg(target_os = "android")]
-pub use async_layer::AndroidTraceAsyncLayer;
+#[cfg(any(target_os = "android", test))]
+mod combined_layer;
+#[cfg(any(target_os = "android", test))]
+pub use combined_layer::AndroidTraceCombinedLayer;
 
-#[cfg(target_os = "android")]
+#[cfg(any(target_os = "android", test))]
+mod diagnostics;
+#[cfg(any(target_os = "android", test))]
+pub use diagnostics::{Diagnostic, DiagnosticKind, DiagnosticsSink, LogcatSink};
+
+#[cfg(any(target_os = "android", test))]
+mod fields;
+#[cfg(any(target_os = "android", test))]
+mod filter;
+#[cfg(any(target_os = "android", test))]
+pub use filter::AndroidTraceFilter;
+#[cfg(any(target_os = "android", test))]
+mod lanes;
+
+#[cfg(any(target_os = "android", test))]
+mod log_layer;
+#[cfg(any(target_os = "android", test))]
+pub use async_layer::{AndroidTraceAsyncLayer, AsyncSectionMode};
+#[cfg(any(target_os = "android", test))]
+pub use log_layer::{AndroidLogLayer, LogWriter, LogcatWriter};
+
+#[cfg(all(any(target_os = "android", test), feature = "rayon"))]
+mod rayon_pool;
+#[cfg(all(any(target_os = "android", test), feature = "rayon"))]
+pub use rayon_pool::TracedThreadPool;
+
+#[cfg(any(target_os = "android", test))]
 mod sync_layer;
-#[cfg(target_os = "android")]
-pub use sync_layer::AndroidTraceLayer;
+#[cfg(any(target_os = "android", test))]
+pub use sync_layer::{AndroidTraceLayer, InterleavedExitStrategy, SectionCloser, ThreadExitGuard};
 
 // TODO: pub use some_mod::ATraceCounterLayer;
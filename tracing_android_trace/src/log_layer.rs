@@ -0,0 +1,260 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::{
+    ffi::{CStr, CString},
+    fmt::Debug,
+};
+
+use android_trace::logcat::{self, LogBuffer, LogPriority};
+use tracing::{Event, Level};
+use tracing_subscriber::{
+    fmt::{format::Writer, FormatFields},
+    layer::Context,
+    registry::LookupSpan,
+    Layer,
+};
+
+use crate::fields::ATraceFields;
+
+/// Writes the messages created by an [`AndroidLogLayer`].
+///
+/// This allows the messages to be sent somewhere other than logcat, such as to check the output of the layer in tests.
+pub trait LogWriter: Debug + Send + Sync + 'static {
+    /// Write `message` to `buffer`, with the given priority and tag.
+    fn write(&self, buffer: LogBuffer, priority: LogPriority, tag: &CStr, message: &CStr);
+}
+
+/// The default [`LogWriter`], which writes to logcat using `__android_log_write`
+/// (or `__android_log_buf_write`, if a buffer other than the main buffer is chosen).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogcatWriter;
+
+impl LogWriter for LogcatWriter {
+    fn write(&self, buffer: LogBuffer, priority: LogPriority, tag: &CStr, message: &CStr) {
+        if buffer == LogBuffer::Main {
+            logcat::write(priority, tag, message);
+        } else {
+            logcat::buf_write(buffer, priority, tag, message);
+        }
+    }
+}
+
+/// A [`tracing_subscriber::Layer`] which writes `tracing` events to the Android log (logcat).
+///
+/// Events are logged with a priority matching their [`Level`], and by default use their target
+/// as the log tag.
+///
+/// ## Usage
+///
+/// ```no_run
+/// # use tracing_subscriber::prelude::*;
+/// use tracing_android_trace::{AndroidLogLayer, AndroidTraceLayer};
+///
+/// tracing_subscriber::registry()
+///     .with(AndroidTraceLayer::new())
+///     .with(AndroidLogLayer::new().with_tag("my_app").with_span_path(true))
+///     .try_init()
+///     .unwrap();
+///
+/// let _span = tracing::info_span!("load").entered();
+/// // Logged as "load: Loading file path=\"config.toml\"", with the tag "my_app"
+/// tracing::info!(path = "config.toml", "Loading file");
+/// ```
+#[derive(Debug)]
+pub struct AndroidLogLayer<W = LogcatWriter> {
+    writer: W,
+    fmt_fields: ATraceFields,
+    tag: Option<CString>,
+    buffer: LogBuffer,
+    span_path: bool,
+}
+
+impl AndroidLogLayer {
+    /// Create a `AndroidLogLayer` which writes to the main log buffer.
+    pub fn new() -> Self {
+        Self::with_writer(LogcatWriter)
+    }
+}
+
+impl Default for AndroidLogLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W: LogWriter> AndroidLogLayer<W> {
+    /// Create a `AndroidLogLayer` which writes its messages using `writer`.
+    pub fn with_writer(writer: W) -> Self {
+        Self {
+            writer,
+            fmt_fields: ATraceFields::new(),
+            tag: None,
+            buffer: LogBuffer::Main,
+            span_path: false,
+        }
+    }
+
+    /// Use `tag` as the log tag for all events, rather than the target of each event.
+    ///
+    /// # Panics
+    ///
+    /// If `tag` contains a null byte.
+    #[must_use]
+    pub fn with_tag(mut self, tag: &str) -> Self {
+        self.tag = Some(CString::new(tag).expect("Log tag should not contain a null byte"));
+        self
+    }
+
+    /// Write events to `buffer`, rather than the main log buffer.
+    #[must_use]
+    pub fn with_buffer(mut self, buffer: LogBuffer) -> Self {
+        self.buffer = buffer;
+        self
+    }
+
+    /// Whether to prefix each message with the names of the spans the event is inside of,
+    /// from the outermost to the innermost.
+    #[must_use]
+    pub fn with_span_path(mut self, span_path: bool) -> Self {
+        self.span_path = span_path;
+        self
+    }
+}
+
+/// The Android log priority used for events with `level`.
+fn priority(level: Level) -> LogPriority {
+    match level {
+        Level::TRACE => LogPriority::Verbose,
+        Level::DEBUG => LogPriority::Debug,
+        Level::INFO => LogPriority::Info,
+        Level::WARN => LogPriority::Warn,
+        Level::ERROR => LogPriority::Error,
+    }
+}
+
+/// Create a C string from `value`, escaping any null bytes rather than dropping the message.
+fn to_cstring(value: String) -> CString {
+    CString::new(value).unwrap_or_else(|e| {
+        let escaped = String::from_utf8_lossy(&e.into_vec()).replace('\0', "\\0");
+        CString::new(escaped).expect("Null bytes were escaped")
+    })
+}
+
+impl<S, W> Layer<S> for AndroidLogLayer<W>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    W: LogWriter,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut message = String::new();
+        if self.span_path {
+            if let Some(scope) = ctx.event_scope(event) {
+                for span in scope.from_root() {
+                    message.push_str(span.name());
+                    message.push(':');
+                }
+                if !message.is_empty() {
+                    message.push(' ');
+                }
+            }
+        }
+        if self
+            .fmt_fields
+            .format_fields(Writer::new(&mut message), event)
+            .is_err()
+        {
+            // Still log something, rather than losing the event entirely
+            message.push_str("<unable to format event>");
+        }
+        let message = to_cstring(message);
+        let target_tag;
+        let tag = match &self.tag {
+            Some(tag) => tag.as_c_str(),
+            None => {
+                target_tag = to_cstring(metadata.target().to_owned());
+                target_tag.as_c_str()
+            }
+        };
+        self.writer
+            .write(self.buffer, priority(*metadata.level()), tag, &message);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    type Written = Arc<Mutex<Vec<(LogPriority, CString, CString)>>>;
+
+    #[derive(Debug, Default)]
+    struct RecordingWriter(Written);
+
+    impl LogWriter for RecordingWriter {
+        fn write(&self, _: LogBuffer, priority: LogPriority, tag: &CStr, message: &CStr) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((priority, tag.to_owned(), message.to_owned()));
+        }
+    }
+
+    fn record(
+        layer: impl FnOnce(RecordingWriter) -> AndroidLogLayer<RecordingWriter>,
+        f: impl FnOnce(),
+    ) -> Written {
+        let written = Written::default();
+        let subscriber =
+            tracing_subscriber::registry().with(layer(RecordingWriter(Arc::clone(&written))));
+        tracing::subscriber::with_default(subscriber, f);
+        written
+    }
+
+    #[test]
+    fn events_use_target_and_level() {
+        let written = record(AndroidLogLayer::with_writer, || {
+            tracing::warn!(target: "my_target", count = 3, "Something happened");
+        });
+        let written = written.lock().unwrap();
+        assert_eq!(
+            *written,
+            [(
+                LogPriority::Warn,
+                c"my_target".to_owned(),
+                c"Something happened count=3".to_owned()
+            )],
+            "The event should use its target as the tag"
+        );
+    }
+
+    #[test]
+    fn span_path_and_tag() {
+        let written = record(
+            |writer| {
+                AndroidLogLayer::with_writer(writer)
+                    .with_tag("app")
+                    .with_span_path(true)
+            },
+            || {
+                let _outer = tracing::info_span!("outer").entered();
+                let _inner = tracing::info_span!("inner").entered();
+                tracing::trace!("Nested");
+            },
+        );
+        let written = written.lock().unwrap();
+        assert_eq!(
+            *written,
+            [(
+                LogPriority::Verbose,
+                c"app".to_owned(),
+                c"outer:inner: Nested".to_owned()
+            )],
+            "The message should be prefixed by the spans, from outermost to innermost"
+        );
+    }
+}
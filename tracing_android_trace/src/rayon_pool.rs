@@ -0,0 +1,201 @@
+// Copyright 2024 the Android Trace Authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::{
+    ffi::CString,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+};
+
+use android_trace::AndroidTrace;
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder};
+use tracing::Span;
+
+/// A [`rayon::ThreadPool`] which propagates the current span into the jobs it runs, and records
+/// its activity as Android Trace counters.
+///
+/// Jobs run using [`spawn`](Self::spawn) or [`install`](Self::install) are run inside the span which was
+/// current when they were submitted.
+/// This means that, when an [`AndroidTraceLayer`](crate::AndroidTraceLayer) is in use, each job is shown
+/// as a section on its worker thread, named after the span it was submitted from.
+///
+/// The following counters are recorded, where `name` is the name given to [`build`](Self::build):
+/// - `{name}.workers`: The number of worker threads which have started and not yet exited.
+/// - `{name}.active_workers`: The number of jobs currently running.
+/// - `{name}.pending_jobs`: The number of jobs which have been submitted, but not yet started.
+///
+/// Note that jobs submitted using [`pool`](Self::pool) directly (including nested jobs from
+/// [`rayon::join`] or parallel iterators) don't propagate the span, and aren't counted.
+///
+/// ## Usage
+///
+/// ```no_run
+/// use tracing_android_trace::TracedThreadPool;
+///
+/// let pool = TracedThreadPool::build(rayon::ThreadPoolBuilder::new(), "assets").unwrap();
+///
+/// let _span = tracing::info_span!("load textures").entered();
+/// // Shown as a "load textures" section on a worker thread
+/// pool.spawn(|| {
+///     // Decode a texture
+/// });
+/// ```
+#[derive(Debug)]
+pub struct TracedThreadPool {
+    pool: ThreadPool,
+    counters: Arc<PoolCounters>,
+}
+
+impl TracedThreadPool {
+    /// Build the thread pool configured by `builder`, with the counters prefixed by `name`.
+    ///
+    /// This replaces the start and exit handlers of `builder`.
+    ///
+    /// # Errors
+    ///
+    /// If the thread pool could not be created.
+    pub fn build(builder: ThreadPoolBuilder, name: &str) -> Result<Self, ThreadPoolBuildError> {
+        let counters = Arc::new(PoolCounters::new(name));
+        let start_counters = Arc::clone(&counters);
+        let exit_counters = Arc::clone(&counters);
+        let pool = builder
+            .start_handler(move |_| start_counters.workers.add(1))
+            .exit_handler(move |_| exit_counters.workers.add(-1))
+            .build()?;
+        Ok(Self { pool, counters })
+    }
+
+    /// Spawn a job onto the pool, which runs inside the current span.
+    ///
+    /// See [`rayon::ThreadPool::spawn`].
+    pub fn spawn<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let job = self.job(f);
+        self.pool.spawn(job);
+    }
+
+    /// Run `op` inside the pool, and inside the current span.
+    ///
+    /// See [`rayon::ThreadPool::install`].
+    pub fn install<OP, R>(&self, op: OP) -> R
+    where
+        OP: FnOnce() -> R + Send,
+        R: Send,
+    {
+        let job = self.job(op);
+        self.pool.install(job)
+    }
+
+    /// The underlying thread pool.
+    pub fn pool(&self) -> &ThreadPool {
+        &self.pool
+    }
+
+    /// Wrap `f` so that it runs inside the current span, and updates the counters.
+    fn job<F, R>(&self, f: F) -> impl FnOnce() -> R + Send
+    where
+        F: FnOnce() -> R + Send,
+    {
+        let span = Span::current();
+        let pending = PendingGuard::new(Arc::clone(&self.counters));
+        move || {
+            let counters = Arc::clone(&pending.0);
+            drop(pending);
+            counters.active_workers.add(1);
+            let _active = ActiveGuard(&counters);
+            span.in_scope(f)
+        }
+    }
+}
+
+/// Counts a job as pending until it starts, or until it is dropped without running.
+struct PendingGuard(Arc<PoolCounters>);
+
+impl PendingGuard {
+    fn new(counters: Arc<PoolCounters>) -> Self {
+        counters.pending_jobs.add(1);
+        Self(counters)
+    }
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.0.pending_jobs.add(-1);
+    }
+}
+
+/// Decrements the active workers when the job ends, including if it panics.
+struct ActiveGuard<'a>(&'a PoolCounters);
+
+impl Drop for ActiveGuard<'_> {
+    fn drop(&mut self) {
+        self.0.active_workers.add(-1);
+    }
+}
+
+#[derive(Debug)]
+struct PoolCounters {
+    workers: Counter,
+    active_workers: Counter,
+    pending_jobs: Counter,
+}
+
+impl PoolCounters {
+    fn new(name: &str) -> Self {
+        let trace = AndroidTrace::new();
+        let counter = |suffix: &str| Counter {
+            trace: trace.clone(),
+            name: CString::new(format!("{name}.{suffix}").replace('\0', "\\0")).unwrap_or_default(),
+            value: AtomicI64::new(0),
+        };
+        Self {
+            workers: counter("workers"),
+            active_workers: counter("active_workers"),
+            pending_jobs: counter("pending_jobs"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Counter {
+    trace: AndroidTrace,
+    name: CString,
+    value: AtomicI64,
+}
+
+impl Counter {
+    fn add(&self, delta: i64) {
+        let value = self.value.fetch_add(delta, Ordering::Relaxed) + delta;
+        if self.trace.is_enabled().unwrap_or(false) {
+            self.trace.set_counter(&self.name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+
+    use super::TracedThreadPool;
+
+    #[test]
+    fn dropped_jobs_are_not_pending() {
+        let pool = TracedThreadPool::build(rayon::ThreadPoolBuilder::new().num_threads(1), "test")
+            .unwrap();
+        let pending = || pool.counters.pending_jobs.value.load(Ordering::Relaxed);
+        let job = pool.job(|| ());
+        assert_eq!(pending(), 1, "A submitted job should be pending");
+        drop(job);
+        assert_eq!(
+            pending(),
+            0,
+            "A job dropped without running shouldn't be pending"
+        );
+        pool.install(|| ());
+        assert_eq!(pending(), 0, "A job which has run shouldn't be pending");
+    }
+}
@@ -4,19 +4,27 @@
 use thread_local::ThreadLocal;
 
 use std::{
+    borrow::Cow,
     cell::RefCell,
     ffi::{CStr, CString},
     fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
 };
 
-use android_trace::AndroidTrace;
-use tracing::span::{self, Id};
-use tracing_subscriber::{
-    fmt::{
-        format::{DefaultFields, Writer},
-        FormatFields,
-    },
-    registry::LookupSpan,
+use android_trace::{allocator::thread_allocated_bytes, cookie::CookieAllocator, AndroidTrace};
+use tracing::{
+    span::{self, Id},
+    subscriber::Interest,
+    Metadata,
+};
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+use crate::{
+    diagnostics::{Diagnostic, DiagnosticKind, Diagnostics, DiagnosticsSink, Report},
+    fields::{self, ATraceFields, LazyName},
 };
 
 /// A [`tracing_subscriber::Layer`] which uses [`ATrace_beginSection`](AndroidTrace::begin_section)
@@ -52,22 +60,177 @@ use tracing_subscriber::{
 ///
 /// [tracing] does not guarantee that spans are exited in a true stack.
 /// This is mismatched with the assumptions made by `ATrace_beginSection` and `ATrace_endSection`.
-/// To work around this, this layer tears down all spans "above" an exiting span, then re-opens them.
-/// In this situation, we currently include a span with the name `_` to ensure that the child
-/// spans appear continuous, but this strategy might change - feedback welcome.
+/// To work around this, this layer tears down all spans "above" an exiting span, then handles
+/// them according to its [`InterleavedExitStrategy`].
+/// By default, the spans above are re-opened, with a section named `_` in place of the exiting span
+/// to ensure that the child spans appear continuous.
+/// The strategy can be chosen using [`with_interleaved_exit_strategy`](Self::with_interleaved_exit_strategy),
+/// and [`interleaved_exit_count`](Self::interleaved_exit_count) reports how often each strategy was used.
 ///
 /// This may lead to spurious gaps in a trace in the prescense of interleaved spans.
+///
+/// Thread-matched sections can only be ended on the thread which began them.
+/// If a span is exited on a different thread to the one it was entered on (such as in some
+/// work-stealing executors), its section is instead ended by the thread it was entered on,
+/// as soon as that thread next enters or exits a span.
+/// An [`AndroidTraceAsyncLayer`](crate::AndroidTraceAsyncLayer) is more suitable for such spans.
+///
+/// Spans are shown whenever they are entered whilst tracing is enabled, even if they were
+/// created before tracing was started.
+/// The fields of a span are only formatted if tracing is enabled when it is created, so spans
+/// created before tracing was started are shown with only their name, unless
+/// [`with_fields_after_enable`](Self::with_fields_after_enable) is used.
+/// The full name of each span is only created when it is first entered whilst tracing is enabled.
+///
+/// ## Allocations
+///
+/// If [`with_allocation_counters`](Self::with_allocation_counters) is used, the number of bytes
+/// allocated by the current thread whilst each span was entered is recorded when it is exited, as the
+/// `allocated bytes: {name}` counter.
+/// This requires a [`TracingAllocator`](android_trace::allocator::TracingAllocator) to be the global allocator.
 #[derive(Debug)]
 pub struct AndroidTraceLayer {
     trace: AndroidTrace,
-    fmt_fields: DefaultFields,
-    current_actual_stack: ThreadLocal<RefCell<ThreadLocalData>>,
+    fmt_fields: ATraceFields,
+    current_actual_stack: Arc<ThreadLocal<ThreadLocalData>>,
+    diagnostics: Diagnostics,
+    is_supported: bool,
+    could_use_api_level_29: bool,
+    interleaved_exit_strategy: InterleavedExitStrategy,
+    interleaved_exit_counts: [AtomicU64; InterleavedExitStrategy::COUNT],
+    cookies: &'static CookieAllocator,
+    allocation_counters: bool,
+    fields_after_enable: bool,
+}
+
+/// How an [`AndroidTraceLayer`] handles a span being exited whilst spans which were entered
+/// after it on the same thread are still entered.
+///
+/// For example, if span `A` is entered, then span `B` is entered, then `A` is exited, then
+/// `B` is exited.
+/// Thread-matched sections must be strictly nested, so this can't be represented exactly.
+/// In each strategy, the section for `A` ends when `A` is exited, and the strategy
+/// controls what happens to the sections of `B` (and any other spans entered after `A` on the
+/// same thread which are still entered), which are referred to as the children below.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum InterleavedExitStrategy {
+    /// Re-open the sections of the spans above the exiting span, with a section named `_` in place
+    /// of the exiting span until they have all exited.
+    #[default]
+    Placeholder,
+    /// As [`Placeholder`](Self::Placeholder), but name the placeholder section after the
+    /// exiting span, with a ` (cont.)` suffix.
+    ContinuationSuffix,
+    /// Re-open the sections of the spans above the exiting span, without any placeholder.
+    ///
+    /// This means that these spans are shown as direct children of the exiting span's parent.
+    ReopenChildren,
+    /// End the sections of the children, and record the remainder of each child as an async
+    /// section named after it, which ends when the child is exited.
+    ///
+    /// This requires Android API level 29.
+    /// If this is not available, [`ReopenChildren`](Self::ReopenChildren) is used instead.
+    DemoteChildrenToAsync,
+    /// End the sections of the children without re-opening them.
+    ///
+    /// This means that the remainder of each child is not shown.
+    DropChildren,
+}
+
+impl InterleavedExitStrategy {
+    const COUNT: usize = 5;
+
+    fn index(self) -> usize {
+        match self {
+            Self::Placeholder => 0,
+            Self::ContinuationSuffix => 1,
+            Self::ReopenChildren => 2,
+            Self::DemoteChildrenToAsync => 3,
+            Self::DropChildren => 4,
+        }
+    }
 }
 
 #[derive(Debug, Default)]
 struct ThreadLocalData {
-    stack: Vec<Option<Id>>,
-    extra_unclosed_values: u32,
+    /// An entry for each time a span has been entered (and not yet exited) on this thread,
+    /// along with the placeholder sections.
+    stack: RefCell<Vec<StackEntry>>,
+    /// The spans entered on this thread which were exited on other threads.
+    remote_exits: Arc<RemoteExits>,
+}
+
+/// The spans which were exited on a different thread to the one they were entered on.
+///
+/// Their sections can only be ended on the thread they were entered on, so they are passed
+/// to that thread, which ends them the next time it enters or exits a span.
+#[derive(Debug, Default)]
+struct RemoteExits {
+    /// Whether `ids` might not be empty, to avoid locking it in the common case.
+    pending: AtomicBool,
+    ids: Mutex<Vec<Id>>,
+}
+
+impl RemoteExits {
+    fn post(&self, id: Id) {
+        self.ids
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(id);
+        self.pending.store(true, Ordering::Release);
+    }
+
+    fn take(&self) -> Vec<Id> {
+        if !self.pending.swap(false, Ordering::Acquire) {
+            return Vec::new();
+        }
+        std::mem::take(&mut *self.ids.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+/// An entry in the stack of a thread.
+#[derive(Debug)]
+enum StackEntry {
+    /// An entry of the span with this id.
+    Span {
+        id: Id,
+        /// Whether the section for this entry is open.
+        ///
+        /// This is false if tracing was disabled when the span was entered, or if the section
+        /// was ended early, such as by an interleaved exit.
+        open: bool,
+        /// The bytes allocated by this thread when the span was entered, if allocation
+        /// counters are enabled and the section was opened.
+        allocated_at_entry: Option<u64>,
+    },
+    /// A section kept open in place of a span which was exited before the spans above it.
+    Placeholder(Cow<'static, CStr>),
+}
+
+impl StackEntry {
+    /// Whether this entry has an open section.
+    fn is_open(&self) -> bool {
+        match self {
+            Self::Span { open, .. } => *open,
+            Self::Placeholder(_) => true,
+        }
+    }
+
+    /// Whether this is an entry of the span with `id`.
+    fn is_span(&self, id: &Id) -> bool {
+        matches!(self, Self::Span { id: entry_id, .. } if entry_id == id)
+    }
+}
+
+/// The name of placeholder sections, unless configured otherwise.
+const EXTRA_STR: &CStr = c"_";
+
+/// The async section of a span which was demoted by [`InterleavedExitStrategy::DemoteChildrenToAsync`].
+#[derive(Debug)]
+struct DemotedSection {
+    name: CString,
+    cookie: i32,
 }
 
 impl AndroidTraceLayer {
@@ -82,12 +245,110 @@ impl AndroidTraceLayer {
     ///
     /// Note that this takes ownership because `AndroidTrace` has a trivial `Clone`
     pub fn with_trace(trace: AndroidTrace) -> Self {
+        let is_supported = trace.is_enabled().is_some();
+        let could_use_api_level_29 = trace.could_use_api_level_29();
         Self {
             trace,
-            fmt_fields: DefaultFields::new(),
-            current_actual_stack: ThreadLocal::new(),
+            fmt_fields: ATraceFields::new(),
+            current_actual_stack: Arc::new(ThreadLocal::new()),
+            diagnostics: Diagnostics::new(),
+            is_supported,
+            could_use_api_level_29,
+            interleaved_exit_strategy: InterleavedExitStrategy::default(),
+            interleaved_exit_counts: Default::default(),
+            cookies: CookieAllocator::global(),
+            allocation_counters: false,
+            fields_after_enable: false,
+        }
+    }
+
+    /// Set how spans which are exited out of order are handled.
+    ///
+    /// See the [caveats](Self#caveats) for more details.
+    ///
+    /// ```no_run
+    /// # use tracing_subscriber::prelude::*;
+    /// use tracing_android_trace::{AndroidTraceLayer, InterleavedExitStrategy};
+    ///
+    /// tracing_subscriber::registry()
+    ///     .with(
+    ///         AndroidTraceLayer::new()
+    ///             .with_interleaved_exit_strategy(InterleavedExitStrategy::ContinuationSuffix),
+    ///     )
+    ///     .try_init()
+    ///     .unwrap();
+    /// ```
+    #[must_use]
+    pub fn with_interleaved_exit_strategy(mut self, strategy: InterleavedExitStrategy) -> Self {
+        self.interleaved_exit_strategy = strategy;
+        self
+    }
+
+    /// Whether to record the bytes allocated whilst each span is entered as a counter.
+    ///
+    /// See the [allocations section](Self#allocations) for more details.
+    #[must_use]
+    pub fn with_allocation_counters(mut self, allocation_counters: bool) -> Self {
+        self.allocation_counters = allocation_counters;
+        self
+    }
+
+    /// Whether to format the fields of spans which are created whilst tracing is disabled.
+    ///
+    /// By default, spans created whilst tracing is disabled are shown with only their name if
+    /// they are entered once tracing is enabled, so that creating a span is cheap whilst not tracing.
+    /// Enabling this shows their fields, at the cost of formatting the fields of every span.
+    #[must_use]
+    pub fn with_fields_after_enable(mut self, fields_after_enable: bool) -> Self {
+        self.fields_after_enable = fields_after_enable;
+        self
+    }
+
+    /// The number of times that `strategy` has been used to handle a span which was exited out of order.
+    ///
+    /// Only the configured strategy is used, except that [`InterleavedExitStrategy::DemoteChildrenToAsync`]
+    /// falls back to [`InterleavedExitStrategy::ReopenChildren`] if async sections are not supported.
+    pub fn interleaved_exit_count(&self, strategy: InterleavedExitStrategy) -> u64 {
+        self.interleaved_exit_counts[strategy.index()].load(Ordering::Relaxed)
+    }
+
+    /// Get a [`SectionCloser`], which can end the sections left open by this layer if a thread
+    /// panics or exits whilst spans are still entered.
+    pub fn section_closer(&self) -> SectionCloser {
+        SectionCloser {
+            trace: self.trace.clone(),
+            stacks: Arc::clone(&self.current_actual_stack),
         }
     }
+
+    /// The number of times that a span was exited more times than this layer recorded it being entered.
+    ///
+    /// Exits of spans which this layer never recorded, such as those entered before the layer
+    /// was added, are ignored without being counted.
+    ///
+    /// This is the same as the [`diagnostic_count`](Self::diagnostic_count) of [`DiagnosticKind::UnbalancedExit`].
+    pub fn unmatched_exit_count(&self) -> u64 {
+        self.diagnostic_count(DiagnosticKind::UnbalancedExit)
+    }
+
+    /// Report problems encountered by this layer to `sink`, instead of to logcat.
+    ///
+    /// See [`DiagnosticsSink`] for an example.
+    #[must_use]
+    pub fn with_diagnostics_sink(mut self, sink: impl DiagnosticsSink) -> Self {
+        self.diagnostics.set_sink(Arc::new(sink));
+        self
+    }
+
+    /// The number of problems of the given kind which this layer has encountered.
+    pub fn diagnostic_count(&self, kind: DiagnosticKind) -> u64 {
+        self.diagnostics.count(kind)
+    }
+
+    /// Report problems to `sink`, sharing it with other layers.
+    pub(crate) fn set_diagnostics_sink(&mut self, sink: Arc<dyn DiagnosticsSink>) {
+        self.diagnostics.set_sink(sink);
+    }
 }
 
 impl Default for AndroidTraceLayer {
@@ -98,13 +359,459 @@ impl Default for AndroidTraceLayer {
 
 #[derive(Debug)]
 struct ATraceExtension {
-    name: CString,
+    name: LazyName,
+    /// The exits to be ended by the thread which this span was most recently entered on.
+    entered_on: Option<Arc<RemoteExits>>,
+    /// The name of the counter for the bytes allocated within this span, created at its first exit.
+    allocation_counter: Option<CString>,
+}
+
+impl AndroidTraceLayer {
+    /// Whether this layer could ever record anything on this device.
+    pub(crate) fn is_supported(&self) -> bool {
+        self.is_supported
+    }
+
+    /// Whether this layer can currently record anything.
+    fn is_enabled(&self) -> bool {
+        self.trace.is_enabled().unwrap_or(false)
+    }
+
+    /// Capture the fields of a new span, if they might be needed for its name.
+    pub(crate) fn capture(&self, attrs: &span::Attributes<'_>) -> Option<LazyName> {
+        if !self.fields_after_enable && !self.is_enabled() {
+            return None;
+        }
+        Some(self.fmt_fields.capture(attrs, &self.diagnostics))
+    }
+
+    /// Record that the span `span` should be traced, with the given name.
+    pub(crate) fn record_new_span<'a, S: LookupSpan<'a>>(
+        &self,
+        name: LazyName,
+        span: &SpanRef<'a, S>,
+    ) {
+        // Spans which use the name of their callsite also keep it here, so that entering
+        // them never needs to look up the name of their callsite
+        span.extensions_mut()
+            .insert::<ATraceExtension>(ATraceExtension {
+                name,
+                entered_on: None,
+                allocation_counter: None,
+            });
+    }
+
+    /// Begin the section for an entry of the span `id`.
+    fn begin(&self, stack: &mut Vec<StackEntry>, id: &Id, name: &CStr) {
+        self.trace.begin_section(name);
+        stack.push(StackEntry::Span {
+            id: id.clone(),
+            open: true,
+            allocated_at_entry: None,
+        });
+        if self.allocation_counters {
+            // Read after pushing, so that growing the stack isn't counted
+            if let Some(StackEntry::Span {
+                allocated_at_entry, ..
+            }) = stack.last_mut()
+            {
+                *allocated_at_entry = Some(thread_allocated_bytes());
+            }
+        }
+    }
+
+    /// Record the bytes allocated whilst `span` was entered, given the value of
+    /// [`thread_allocated_bytes`] when it was entered and exited.
+    fn record_allocations<'a, S: LookupSpan<'a>>(
+        &self,
+        span: &SpanRef<'a, S>,
+        allocated_at_entry: u64,
+        allocated_at_exit: u64,
+    ) {
+        let allocated = allocated_at_exit.saturating_sub(allocated_at_entry);
+        let mut extensions = span.extensions_mut();
+        let Some(ext) = extensions.get_mut::<ATraceExtension>() else {
+            return;
+        };
+        if ext.allocation_counter.is_none() {
+            let Some(name) = ext.name.ready() else {
+                return;
+            };
+            let mut counter = b"allocated bytes: ".to_vec();
+            counter.extend_from_slice(name.to_bytes());
+            ext.allocation_counter =
+                Some(CString::new(counter).expect("Section names don't contain null bytes"));
+        }
+        if let Some(counter) = &ext.allocation_counter {
+            self.trace
+                .set_counter(counter, i64::try_from(allocated).unwrap_or(i64::MAX));
+        }
+    }
+
+    /// End the sections of any placeholders which no longer have any open sections above them.
+    fn end_placeholders(&self, stack: &mut Vec<StackEntry>) {
+        let mut index = stack.len();
+        while index > 0 {
+            index -= 1;
+            match stack[index] {
+                StackEntry::Placeholder(_) => {
+                    stack.remove(index);
+                    self.trace.end_section();
+                }
+                StackEntry::Span { open: true, .. } => break,
+                StackEntry::Span { open: false, .. } => {}
+            }
+        }
+    }
+
+    /// Handle `id` being exited on a different thread to the one it was entered on.
+    ///
+    /// Its section can only be ended on the thread it was entered on, so the exit is passed to
+    /// that thread, which ends the section when it next enters or exits a span.
+    fn exit_on_other_thread<S>(&self, id: &Id, ctx: &tracing_subscriber::layer::Context<'_, S>)
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        let extensions = span.extensions();
+        let Some(owner) = extensions
+            .get::<ATraceExtension>()
+            .and_then(|ext| ext.entered_on.as_ref())
+        else {
+            // This layer never recorded an entry of the span, so there is nothing to end
+            return;
+        };
+        let current = self.current_actual_stack.get_or_default();
+        if Arc::ptr_eq(owner, &current.remote_exits) {
+            // The span was last entered on this thread, but has already been exited
+            self.diagnostics.report(Diagnostic::new(
+                DiagnosticKind::UnbalancedExit,
+                span.metadata(),
+            ));
+            return;
+        }
+        owner.post(id.clone());
+    }
+
+    /// End the sections of the spans which were entered on this thread, but exited on another thread.
+    fn process_remote_exits<S>(
+        &self,
+        data: &ThreadLocalData,
+        stack: &mut Vec<StackEntry>,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+        diagnostics: &impl Report,
+    ) where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        for id in data.remote_exits.take() {
+            if let Some(index) = stack.iter().rposition(|entry| entry.is_span(&id)) {
+                // The other thread's allocations aren't meaningful for this exit
+                self.exit_entry(stack, index, ctx, diagnostics);
+            } else if let Some(span) = ctx.span(&id) {
+                diagnostics.report(Diagnostic::new(
+                    DiagnosticKind::UnbalancedExit,
+                    span.metadata(),
+                ));
+            }
+        }
+    }
+
+    /// Remove the entry at `index`, which has been exited, and end its section.
+    fn exit_entry<S>(
+        &self,
+        stack: &mut Vec<StackEntry>,
+        index: usize,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+        diagnostics: &impl Report,
+    ) where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let StackEntry::Span {
+            id: ref exiting_id,
+            open,
+            ..
+        } = stack[index]
+        else {
+            unreachable!("Only span entries are exited");
+        };
+        let exiting_id = exiting_id.clone();
+        if !open {
+            // The section was demoted, dropped or closed, so nothing above it depends on it
+            stack.remove(index);
+            self.end_demoted(&exiting_id, ctx);
+        } else if !stack[index + 1..].iter().any(StackEntry::is_open) {
+            // Fast path, if this is the innermost open section
+            // Matches the call in `on_enter`
+            stack.remove(index);
+            self.trace.end_section();
+        } else {
+            // We need to handle the case where span opening and closing is interleaved
+            // E.g. open A, open B, close A, close B
+            let strategy = match self.interleaved_exit_strategy {
+                InterleavedExitStrategy::DemoteChildrenToAsync if !self.could_use_api_level_29 => {
+                    InterleavedExitStrategy::ReopenChildren
+                }
+                strategy => strategy,
+            };
+            self.interleaved_exit_counts[strategy.index()].fetch_add(1, Ordering::Relaxed);
+
+            for _ in stack[index..].iter().filter(|entry| entry.is_open()) {
+                self.trace.end_section();
+            }
+            match strategy {
+                InterleavedExitStrategy::Placeholder => {
+                    stack[index] = StackEntry::Placeholder(Cow::Borrowed(EXTRA_STR));
+                    self.reopen(&stack[index..], ctx, diagnostics);
+                }
+                InterleavedExitStrategy::ContinuationSuffix => {
+                    // The span might have been closed since it was exited on another thread
+                    let name = ctx.span(&exiting_id).and_then(|span| {
+                        with_section_name(&span, |name| {
+                            let mut name = name.to_bytes().to_vec();
+                            name.extend_from_slice(b" (cont.)");
+                            CString::new(name).expect("Section names don't contain null bytes")
+                        })
+                    });
+                    stack[index] =
+                        StackEntry::Placeholder(name.map_or(Cow::Borrowed(EXTRA_STR), Cow::Owned));
+                    self.reopen(&stack[index..], ctx, diagnostics);
+                }
+                InterleavedExitStrategy::ReopenChildren => {
+                    stack.remove(index);
+                    self.reopen(&stack[index..], ctx, diagnostics);
+                }
+                InterleavedExitStrategy::DemoteChildrenToAsync => {
+                    stack.remove(index);
+                    self.demote(&stack[index..], ctx);
+                    close_entries(stack, index);
+                }
+                InterleavedExitStrategy::DropChildren => {
+                    stack.remove(index);
+                    close_entries(stack, index);
+                }
+            }
+        }
+        // Clear all the dangling placeholders on the stack
+        self.end_placeholders(stack);
+    }
+
+    /// Begin a section for each open entry in `entries`, which had previously been ended.
+    fn reopen<S>(
+        &self,
+        entries: &[StackEntry],
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+        diagnostics: &impl Report,
+    ) where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        for entry in entries {
+            match entry {
+                StackEntry::Span { id, open: true, .. } => {
+                    let span = ctx.span(id).expect("Span not found, this is a bug");
+                    if with_section_name(&span, |name| self.trace.begin_section(name)).is_none() {
+                        diagnostics.report(Diagnostic::new(
+                            DiagnosticKind::UnexpectedStackState,
+                            span.metadata(),
+                        ));
+                    }
+                }
+                StackEntry::Placeholder(name) => self.trace.begin_section(name),
+                StackEntry::Span { open: false, .. } => {}
+            }
+        }
+    }
+
+    /// Record the remainder of the open entries in `entries` as async sections.
+    fn demote<S>(&self, entries: &[StackEntry], ctx: &tracing_subscriber::layer::Context<'_, S>)
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        for entry in entries {
+            let StackEntry::Span { id, open: true, .. } = entry else {
+                continue;
+            };
+            let span = ctx.span(id).expect("Span not found, this is a bug");
+            let Some(name) = with_section_name(&span, CStr::to_owned) else {
+                continue;
+            };
+            let mut extensions = span.extensions_mut();
+            // A span which was re-entered can only have one of its entries demoted
+            if extensions.get_mut::<DemotedSection>().is_some() {
+                continue;
+            }
+            let cookie = self.cookies.allocate(&name);
+            self.trace.begin_async_section(&name, cookie);
+            extensions.insert(DemotedSection { name, cookie });
+        }
+    }
+
+    /// End the async section of a span which was demoted by [`InterleavedExitStrategy::DemoteChildrenToAsync`], if any.
+    fn end_demoted<S>(&self, id: &Id, ctx: &tracing_subscriber::layer::Context<'_, S>)
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        // The span might have been closed since it was exited on another thread
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let demoted = span.extensions_mut().remove::<DemotedSection>();
+        if let Some(DemotedSection { name, cookie }) = demoted {
+            self.trace.end_async_section(&name, cookie);
+            self.cookies.free(&name, cookie);
+        }
+    }
+}
+
+/// Record that the sections of the entries in `stack` from `start` have been ended, removing
+/// any placeholders.
+fn close_entries(stack: &mut Vec<StackEntry>, start: usize) {
+    let mut index = start;
+    while index < stack.len() {
+        match &mut stack[index] {
+            StackEntry::Span { open, .. } => {
+                *open = false;
+                index += 1;
+            }
+            StackEntry::Placeholder(_) => {
+                stack.remove(index);
+            }
+        }
+    }
+}
+
+/// Call `f` with the name of the section for `span`, if it has been created.
+fn with_section_name<'a, S: LookupSpan<'a>, R>(
+    span: &SpanRef<'a, S>,
+    f: impl FnOnce(&CStr) -> R,
+) -> Option<R> {
+    let extensions = span.extensions();
+    // The name was formatted when this span was entered
+    extensions
+        .get::<ATraceExtension>()
+        .and_then(|ext| ext.name.ready())
+        .map(f)
+}
+
+/// Ends the sections left open by an [`AndroidTraceLayer`] when a thread panics or exits.
+///
+/// Android shows a section which is never ended as running until the end of the trace.
+/// This can happen if a thread exits whilst spans are still entered, or if the process
+/// aborts due to a panic.
+/// Note that when a panic unwinds, the guards of entered spans will exit them as normal.
+///
+/// Created using [`AndroidTraceLayer::section_closer`] (or [`AndroidTraceCombinedLayer::section_closer`](crate::AndroidTraceCombinedLayer::section_closer)).
+/// Spans which are exited after their section was ended by this are otherwise ignored.
+///
+/// ## Usage
+///
+/// ```no_run
+/// # use tracing_subscriber::prelude::*;
+/// use tracing_android_trace::AndroidTraceLayer;
+///
+/// let layer = AndroidTraceLayer::new();
+/// let closer = layer.section_closer();
+/// closer.clone().install_panic_hook();
+/// tracing_subscriber::registry().with(layer).try_init().unwrap();
+///
+/// std::thread::spawn(move || {
+///     let _guard = closer.thread_exit_guard();
+///     // ...
+/// });
+/// ```
+#[derive(Debug, Clone)]
+pub struct SectionCloser {
+    trace: AndroidTrace,
+    stacks: Arc<ThreadLocal<ThreadLocalData>>,
+}
+
+impl SectionCloser {
+    /// End every section which is open on the current thread.
+    ///
+    /// Spans which are currently entered on this thread are still known to be entered,
+    /// so that exiting them later is not treated as a mistake.
+    pub fn close_current_thread(&self) {
+        self.close(false);
+    }
+
+    /// Install a panic hook which records a `panic: <message>` marker section.
+    ///
+    /// If panics abort (i.e. with `panic = "abort"`), the hook also ends every section which is open
+    /// on the panicking thread.
+    /// Otherwise, the sections are left open, as the panic might be caught with
+    /// [`catch_unwind`](std::panic::catch_unwind), and the spans exited whilst unwinding end
+    /// their own sections.
+    ///
+    /// The previously installed panic hook is called afterwards.
+    pub fn install_panic_hook(self) {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if self.trace.is_enabled().unwrap_or(false) {
+                let payload = info.payload();
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("Box<dyn Any>");
+                // Null bytes are removed rather than losing the marker entirely
+                let marker = format!("panic: {message}").replace('\0', "");
+                if let Ok(marker) = CString::new(marker) {
+                    self.trace.begin_section(&marker);
+                    self.trace.end_section();
+                }
+            }
+            if cfg!(panic = "abort") {
+                self.close_current_thread();
+            }
+            previous(info);
+        }));
+    }
+
+    /// Create a guard which ends every section which is open on the current thread when dropped.
+    ///
+    /// This should be created at the start of a thread, so that it is dropped when the thread exits.
+    pub fn thread_exit_guard(&self) -> ThreadExitGuard {
+        ThreadExitGuard {
+            closer: self.clone(),
+        }
+    }
+
+    fn close(&self, thread_exiting: bool) {
+        let Some(data) = self.stacks.get() else {
+            return;
+        };
+        // If this thread panicked whilst this layer was using the stack, the state can't be trusted
+        let Ok(mut stack) = data.stack.try_borrow_mut() else {
+            return;
+        };
+        for _ in stack.iter().filter(|entry| entry.is_open()) {
+            self.trace.end_section();
+        }
+        if thread_exiting {
+            // The thread can't exit these spans, so forget about them.
+            // This also ensures that a later thread which reuses this thread's data isn't affected.
+            stack.clear();
+            data.remote_exits.take();
+        } else {
+            close_entries(&mut stack, 0);
+        }
+    }
+}
+
+/// Ends every section which is open on the thread it was created on, when dropped.
+///
+/// Created using [`SectionCloser::thread_exit_guard`].
+#[derive(Debug)]
+#[must_use = "The sections are ended when this guard is dropped"]
+pub struct ThreadExitGuard {
+    closer: SectionCloser,
+}
+
+impl Drop for ThreadExitGuard {
+    fn drop(&mut self) {
+        self.closer.close(true);
+    }
 }
 
-#[allow(
-    clippy::print_stderr,
-    // reason = "tracing::warn could lead to an infinite loop inside the tracing layer"
-)]
 impl<S> tracing_subscriber::Layer<S> for AndroidTraceLayer
 where
     S: tracing::Subscriber + for<'a> LookupSpan<'a>,
@@ -115,36 +822,21 @@ where
         id: &Id,
         ctx: tracing_subscriber::layer::Context<'_, S>,
     ) {
-        if self.trace.is_enabled().unwrap_or(false) {
+        if !self.is_supported() {
+            return;
+        }
+        // Spans whose fields weren't captured are given an extension when first entered whilst tracing
+        if let Some(name) = self.capture(attrs) {
             let span = ctx.span(id).expect("Span not found, this is a bug");
-            let mut extensions = span.extensions_mut();
-            let mut name = String::from(attrs.metadata().name());
-            name.push_str(": ");
-            if self
-                .fmt_fields
-                .format_fields(Writer::new(&mut name), attrs)
-                .is_ok()
-            {
-                let name = CString::new(name);
-                match name {
-                    Ok(name) => extensions.insert::<ATraceExtension>(ATraceExtension { name }),
-                    Err(e) => eprintln!(
-                        concat!(
-                            "[tracing_android_trace] Unable to format the following ",
-                            "span due to a null byte ({:?}), ignoring: {:?}",
-                        ),
-                        e, attrs
-                    ),
-                }
-            } else {
-                eprintln!(
-                    "[tracing_android_trace] Unable to format the following event, ignoring: {:?}",
-                    attrs
-                );
-            }
+            self.record_new_span(name, &span);
         }
     }
 
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        fields::register_callsite(metadata);
+        Interest::always()
+    }
+
     fn on_record(
         &self,
         _span: &Id,
@@ -169,114 +861,299 @@ where
     }
 
     fn on_enter(&self, id: &Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if !self.is_supported() {
+            return;
+        }
+        // Created before borrowing the stack, so that it reports after the stack is released
+        let diagnostics = self.diagnostics.deferred();
+        let data = self.current_actual_stack.get_or_default();
+        let mut stack = data.stack.borrow_mut();
+        // Spans exited on other threads can't be the parent of this span
+        self.process_remote_exits(data, &mut stack, &ctx, &diagnostics);
         let span = ctx.span(id).expect("Span not found, this is a bug");
-        let extensions = span.extensions();
-        // The extension is optional in case tracing is disabled
-        if let Some(ext) = extensions.get::<ATraceExtension>() {
-            self.trace.begin_section(&ext.name);
-            let stack = self.current_actual_stack.get_or_default();
-            stack.borrow_mut().stack.push(Some(id.clone()));
+        let mut extensions = span.extensions_mut();
+        // The extension is missing if tracing was disabled when the span was created
+        // (or this layer was added after the span was created)
+        if extensions.get_mut::<ATraceExtension>().is_none() {
+            extensions.insert(ATraceExtension {
+                name: LazyName::without_fields(),
+                entered_on: None,
+                allocation_counter: None,
+            });
+        }
+        let ext = extensions
+            .get_mut::<ATraceExtension>()
+            .expect("Inserted above if missing");
+        // Record which thread to pass the exit to if the span is exited on another thread.
+        // This is needed even if no section is begun, so that the entry is always removed.
+        if !ext
+            .entered_on
+            .as_ref()
+            .is_some_and(|owner| Arc::ptr_eq(owner, &data.remote_exits))
+        {
+            ext.entered_on = Some(Arc::clone(&data.remote_exits));
+        }
+        let name = if self.is_enabled() {
+            ext.name.get(span.metadata(), &diagnostics)
+        } else {
+            None
+        };
+        if let Some(name) = name {
+            self.begin(&mut stack, id, name);
+        } else {
+            // Every entry is recorded, so that its exit is matched to it, even once tracing is enabled
+            stack.push(StackEntry::Span {
+                id: id.clone(),
+                open: false,
+                allocated_at_entry: None,
+            });
         }
     }
 
     fn on_exit(&self, exiting_id: &Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
-        // For some reason, the `S`'s `exit` method is called *before* on_exit, so the span which is exiting is
-        // no longer in the current stack
-        // Because of this, to find the place it *used* to be, we find the item which was the parent of the current item
-        // in the stack
-        let this_span = ctx.span(exiting_id).expect("Span not found, this is a bug");
-        let Some(data) = self.current_actual_stack.get() else {
-            // No spans had the extension, so nothing to do
+        if !self.is_supported() {
             return;
-        };
-        let mut data = data.borrow_mut();
-        let stack = &mut data.stack;
-        if stack.is_empty() {
-            // This span should have been already closed in the case where a previous span was not
-            // found in the stack. This means that tracing was enabled then disabled
-            let extensions = this_span.extensions();
-            if extensions.get::<ATraceExtension>().is_some() {
-                if data.extra_unclosed_values == 0 {
-                    panic!("Internal error: The same span was exited twice?");
-                } else {
-                    data.extra_unclosed_values -= 1;
-                }
-            }
+        }
+        let allocated_at_exit = thread_allocated_bytes();
+        // Created before borrowing the stack, so that it reports after the stack is released
+        let diagnostics = self.diagnostics.deferred();
+        let data = self.current_actual_stack.get_or_default();
+        let mut stack = data.stack.borrow_mut();
+        self.process_remote_exits(data, &mut stack, &ctx, &diagnostics);
+        // The innermost entry of the span is always the one which is exited, so re-entered spans
+        // are closed in the reverse order to which they were entered.
+        let Some(index) = stack.iter().rposition(|entry| entry.is_span(exiting_id)) else {
+            // The span wasn't entered on this thread
+            drop(stack);
+            self.exit_on_other_thread(exiting_id, &ctx);
             return;
+        };
+        if let StackEntry::Span {
+            allocated_at_entry: Some(allocated_at_entry),
+            ..
+        } = stack[index]
+        {
+            let span = ctx.span(exiting_id).expect("Span not found, this is a bug");
+            self.record_allocations(&span, allocated_at_entry, allocated_at_exit);
         }
-        let last = stack.last().unwrap().as_ref();
-        debug_assert!(
-            last.is_some(),
-            "We're exiting a span, so we should have entered one previously"
+        self.exit_entry(&mut stack, index, &ctx, &diagnostics);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use android_trace::recording::{self, Event};
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    /// Run `f` with `layer` whilst tracing is enabled, returning the calls made to Android Trace.
+    fn record(layer: AndroidTraceLayer, f: impl FnOnce()) -> Vec<Event> {
+        recording::set_enabled(true);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, f);
+        recording::take_events()
+    }
+
+    /// Enter `a`, then `b`, then exit `a` before `b`, with the given strategy.
+    fn interleaved(strategy: InterleavedExitStrategy) -> Vec<Event> {
+        let layer = AndroidTraceLayer::new().with_interleaved_exit_strategy(strategy);
+        record(layer, || {
+            let a = tracing::info_span!("a").entered();
+            let b = tracing::info_span!("b").entered();
+            drop(a);
+            tracing::info_span!("c").in_scope(|| {});
+            drop(b);
+        })
+    }
+
+    #[test]
+    fn reentered_span_is_nested() {
+        let events = record(AndroidTraceLayer::new(), || {
+            let span = tracing::info_span!("s");
+            let _outer = span.enter();
+            span.in_scope(|| {});
+        });
+        assert_eq!(
+            events,
+            [
+                Event::Begin("s: ".into()),
+                Event::Begin("s: ".into()),
+                Event::End,
+                Event::End
+            ],
+            "Each entry should have its own section"
         );
-        if last == Some(exiting_id) {
-            stack.pop();
-            // Fast path, if we were at the top of the stack (i.e. the current top is our parent)
-            // Matches the call in `on_enter`
-            self.trace.end_section();
-            #[cfg(debug_assertions)]
-            {
-                let extensions = this_span.extensions();
-                debug_assert!(
-                    extensions.get::<ATraceExtension>().is_some(),
-                    "We believe we handled this span's creation, so it should have the extension"
-                );
-            }
-            // Clear all the dangling items on the stack
-            while let Some(None) = stack.last() {
-                stack.pop();
-                self.trace.end_section();
-            }
-        } else {
-            // We need to handle the case where span opening and closing is interleaved
-            // E.g. open A, open B, close A, close B
-            //
-            // We model this by effectively keeping A open until B is closed, but with a new name
-            // of EXTRA_STR - currently `_`
-            const EXTRA_STR: &CStr = c"_";
+    }
 
-            let mut index_of_this = None;
-            for (idx, item) in stack.iter_mut().enumerate().rev() {
-                self.trace.end_section();
-                if item.as_ref() == Some(exiting_id) {
-                    index_of_this = Some(idx);
-                    *item = None;
-                    break;
-                }
-            }
+    #[test]
+    fn interleaved_placeholder() {
+        assert_eq!(
+            interleaved(InterleavedExitStrategy::Placeholder),
+            [
+                Event::Begin("a: ".into()),
+                Event::Begin("b: ".into()),
+                Event::End,
+                Event::End,
+                Event::Begin("_".into()),
+                Event::Begin("b: ".into()),
+                Event::Begin("c: ".into()),
+                Event::End,
+                Event::End,
+                Event::End,
+            ],
+            "The child should be reopened inside a placeholder, which ends with it"
+        );
+    }
 
-            let Some(index_of_this) = index_of_this else {
-                // There are two cases where this could occur:
-                // 1) The span was created *before* tracing was enabled, then tracing was on and then off, then the span exited
-                // 2) The span was created then exited *after* tracing was disabled, but before all parent spans were exited
-                //
-                // In either case tracing is disabled
-                let extra_values = stack
-                    .len()
-                    .try_into()
-                    .expect("Shouldn't have more than u32::MAX depth of stack");
-                stack.clear();
-                data.extra_unclosed_values = extra_values;
-                let extensions = this_span.extensions();
-                debug_assert!(
-                    extensions.get::<ATraceExtension>().is_none(),
-                    "We don't close a span as we believe it wasn't entered, but it was entered"
-                );
-                return;
-            };
-            for id in stack[index_of_this..].iter() {
-                if let Some(id) = id {
-                    let span = ctx.span(id).expect("Span not found, this is a bug");
-                    let extensions = span.extensions();
-                    if let Some(ext) = extensions.get::<ATraceExtension>() {
-                        self.trace.begin_section(&ext.name);
-                    } else {
-                        eprintln!("Unexpectedly had item in stack without ATraceExtension");
-                    }
-                } else {
-                    self.trace.begin_section(EXTRA_STR);
-                }
-            }
+    #[test]
+    fn interleaved_continuation_suffix() {
+        assert_eq!(
+            interleaved(InterleavedExitStrategy::ContinuationSuffix),
+            [
+                Event::Begin("a: ".into()),
+                Event::Begin("b: ".into()),
+                Event::End,
+                Event::End,
+                Event::Begin("a:  (cont.)".into()),
+                Event::Begin("b: ".into()),
+                Event::Begin("c: ".into()),
+                Event::End,
+                Event::End,
+                Event::End,
+            ],
+            "The placeholder should be named after the exited span"
+        );
+    }
+
+    #[test]
+    fn interleaved_reopen_children() {
+        assert_eq!(
+            interleaved(InterleavedExitStrategy::ReopenChildren),
+            [
+                Event::Begin("a: ".into()),
+                Event::Begin("b: ".into()),
+                Event::End,
+                Event::End,
+                Event::Begin("b: ".into()),
+                Event::Begin("c: ".into()),
+                Event::End,
+                Event::End,
+            ],
+            "The child should be reopened without a placeholder"
+        );
+    }
+
+    #[test]
+    fn interleaved_demote_children_to_async() {
+        let events = interleaved(InterleavedExitStrategy::DemoteChildrenToAsync);
+        let [Event::BeginAsync(_, cookie), ..] = &events[4..] else {
+            panic!("The child should become an async section, got {events:?}");
+        };
+        assert_eq!(
+            events,
+            [
+                Event::Begin("a: ".into()),
+                Event::Begin("b: ".into()),
+                Event::End,
+                Event::End,
+                Event::BeginAsync("b: ".into(), *cookie),
+                Event::Begin("c: ".into()),
+                Event::End,
+                Event::EndAsync("b: ".into(), *cookie),
+            ],
+            "The async section of the child should end when it is exited"
+        );
+    }
+
+    #[test]
+    fn interleaved_drop_children() {
+        assert_eq!(
+            interleaved(InterleavedExitStrategy::DropChildren),
+            [
+                Event::Begin("a: ".into()),
+                Event::Begin("b: ".into()),
+                Event::End,
+                Event::End,
+                Event::Begin("c: ".into()),
+                Event::End,
+            ],
+            "The child should not be reopened"
+        );
+    }
+
+    /// A sink which uses `tracing`, which re-enters the layer.
+    #[derive(Debug)]
+    struct TracingSink;
+
+    impl DiagnosticsSink for TracingSink {
+        fn report(&self, _: &Diagnostic) {
+            tracing::info_span!("report").in_scope(|| {});
         }
     }
+
+    #[test]
+    fn diagnostics_are_reported_after_the_stack_is_released() {
+        let layer = AndroidTraceLayer::new().with_diagnostics_sink(TracingSink);
+        let events = record(layer, || {
+            tracing::info_span!("null", byte = %"\0").in_scope(|| {});
+        });
+        assert_eq!(
+            events,
+            [Event::Begin("report: ".into()), Event::End],
+            "The span with a null byte should be reported, and the sink's span recorded"
+        );
+    }
+
+    #[test]
+    fn remote_exit_of_disabled_entry_is_removed() {
+        let layer = AndroidTraceLayer::new();
+        let stacks = Arc::clone(&layer.current_actual_stack);
+        let events = record(layer, || {
+            let span = tracing::info_span!("s");
+            let id = span.id().unwrap();
+            let dispatch = tracing::dispatcher::get_default(Clone::clone);
+            recording::set_enabled(false);
+            dispatch.enter(&id);
+            recording::set_enabled(true);
+            std::thread::scope(|scope| {
+                scope.spawn(|| dispatch.exit(&id));
+            });
+            tracing::info_span!("other").in_scope(|| {});
+        });
+        assert_eq!(
+            events,
+            [Event::Begin("other: ".into()), Event::End],
+            "No section should be ended for the entry made whilst tracing was disabled"
+        );
+        let stack = stacks.get().unwrap().stack.borrow();
+        assert!(
+            stack.is_empty(),
+            "The entry should be removed by the exit on the other thread, got {stack:?}"
+        );
+    }
+
+    #[test]
+    fn remote_exit_is_ended_by_entering_thread() {
+        let events = record(AndroidTraceLayer::new(), || {
+            let span = tracing::info_span!("s");
+            let id = span.id().unwrap();
+            let dispatch = tracing::dispatcher::get_default(Clone::clone);
+            dispatch.enter(&id);
+            std::thread::scope(|scope| {
+                scope.spawn(|| dispatch.exit(&id));
+            });
+            tracing::info_span!("other").in_scope(|| {});
+        });
+        assert_eq!(
+            events,
+            [
+                Event::Begin("s: ".into()),
+                Event::End,
+                Event::Begin("other: ".into()),
+                Event::End
+            ],
+            "The section should be ended when the entering thread next enters a span"
+        );
+    }
 }